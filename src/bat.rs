@@ -1,26 +1,46 @@
 use bitvec::view::BitView;
 use bitvec::{field::BitField, prelude::Lsb0};
 
-use crate::{error::VhdxError, meta_data::SectorSize, DeSerialise};
+use crate::{error::VhdxError, meta_data::SectorSize, vhdx::Vhdx, DeSerialise};
 
 #[allow(dead_code)]
 pub struct BatTable {
     entries: Vec<BatEntry>,
 }
 
-#[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct BatEntry {
-    state: BatEntryState,
-    file_offset_mb: usize,
+    pub(crate) state: BatEntryState,
+    pub(crate) file_offset_mb: usize,
 }
 impl BatEntry {
-    fn new(state: BatEntryState, file_offset_mb: usize) -> BatEntry {
+    pub(crate) fn new(state: BatEntryState, file_offset_mb: usize) -> BatEntry {
         Self {
             state,
             file_offset_mb,
         }
     }
+
+    // On disk encoding of the entry, the state in the lowest 3 bits followed by 17 reserved bits
+    // and the 44 bit file offset in MB.
+    pub fn to_bits(&self) -> u64 {
+        (self.state as u64 & 0b111) | ((self.file_offset_mb as u64) << 20)
+    }
+
+    pub fn state(&self) -> BatEntryState {
+        self.state
+    }
+
+    // Only blocks that are (partially) present have a meaningful file offset, for every other
+    // state the offset field is reserved.
+    pub fn file_offset(&self) -> Option<u64> {
+        match self.state {
+            BatEntryState::FullyPresent | BatEntryState::PartiallyPresent => {
+                Some(self.file_offset_mb as u64 * Vhdx::MB)
+            }
+            _ => None,
+        }
+    }
 }
 
 impl<T> DeSerialise<T> for BatEntry {
@@ -35,14 +55,14 @@ impl<T> DeSerialise<T> for BatEntry {
         let bits = buffer.view_bits::<Lsb0>();
         let (head, rest) = bits.split_at(3);
         let head_value = head.load::<u8>();
-        let state = BatEntryState::from_bits(head_value);
+        let state = BatEntryState::from_bits(head_value)?;
         let (_, rest) = rest.split_at(17);
         let (head, _) = rest.split_at(44);
         Ok(BatEntry::new(state, head.load::<usize>()))
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BatEntryState {
     NotPresent = 0,
     Undefined = 1,
@@ -50,23 +70,102 @@ pub enum BatEntryState {
     Unmapped = 3,
     FullyPresent = 6,
     PartiallyPresent = 7,
-    Unknown,
+}
+
+// Payload block as seen through the BAT, see Vhdx::blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockInfo {
+    // Index of the block in the virtual disk, sector bitmap entries are not counted
+    pub index: u64,
+    pub state: BatEntryState,
+    // Only set for (partially) present blocks
+    pub file_offset: Option<u64>,
 }
 
 impl BatEntryState {
-    fn from_bits(value: u8) -> Self {
+    // States 4 and 5 are reserved, an entry using them is corrupt and its offset meaningless
+    fn from_bits(value: u8) -> Result<Self, VhdxError> {
         match value {
-            0 => BatEntryState::NotPresent,
-            1 => BatEntryState::Undefined,
-            2 => BatEntryState::Zero,
-            3 => BatEntryState::Unmapped,
-            6 => BatEntryState::FullyPresent,
-            7 => BatEntryState::PartiallyPresent,
-            _ => BatEntryState::Unknown,
+            0 => Ok(BatEntryState::NotPresent),
+            1 => Ok(BatEntryState::Undefined),
+            2 => Ok(BatEntryState::Zero),
+            3 => Ok(BatEntryState::Unmapped),
+            6 => Ok(BatEntryState::FullyPresent),
+            7 => Ok(BatEntryState::PartiallyPresent),
+            _ => Err(VhdxError::InvalidBatState(value)),
         }
     }
 }
 
+// Sector bitmap of a differencing disk, one bit per logical sector telling whether the sector is
+// stored in this file or has to be read from the parent. Bits are ordered from the least
+// significant bit onwards, bit 0 of byte 0 is sector 0 and bit 7 of byte 0 is sector 7.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectorBitmap {
+    bytes: Vec<u8>,
+}
+
+impl SectorBitmap {
+    // A sector bitmap block is 1 MB, covering the sectors of chunk_ratio payload blocks
+    pub const SIZE: usize = 1024 * 1024;
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self { bytes }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    // Sectors beyond the bitmap are never present
+    pub fn is_present(&self, sector: u64) -> bool {
+        self.bytes
+            .view_bits::<Lsb0>()
+            .get(sector as usize)
+            .is_some_and(|bit| *bit)
+    }
+
+    pub fn set_present(&mut self, sector: u64, present: bool) {
+        if let Some(mut bit) = self.bytes.view_bits_mut::<Lsb0>().get_mut(sector as usize) {
+            *bit = present;
+        }
+    }
+}
+
+// Reads a complete sector bitmap block from the current position
+impl<T> DeSerialise<T> for SectorBitmap {
+    type Item = SectorBitmap;
+
+    fn deserialize(reader: &mut T) -> Result<Self::Item, VhdxError>
+    where
+        T: std::io::Read + std::io::Seek,
+    {
+        let offset = reader.stream_position()?;
+        let mut bytes = vec![0; SectorBitmap::SIZE];
+        reader
+            .read_exact(&mut bytes)
+            .map_err(|e| VhdxError::from(e).at_structure("Sector Bitmap", offset))?;
+        Ok(SectorBitmap::from_bytes(bytes))
+    }
+}
+
+// Every chunk_ratio payload block entries are followed by one sector bitmap entry, so the
+// interleaved sector bitmap entries before a payload block have to be skipped.
+pub(crate) fn payload_bat_index(block: u64, chunk_ratio: u64) -> u64 {
+    block + block / chunk_ratio
+}
+
+// Whether a BAT index holds a sector bitmap entry rather than a payload block entry, with a
+// chunk ratio of 1 every other entry is a sector bitmap entry.
+pub fn is_sector_bitmap_index(index: u64, chunk_ratio: u64) -> bool {
+    (index + 1).is_multiple_of(chunk_ratio + 1)
+}
+
+// The sector bitmap entry of a chunk follows the chunk_ratio payload block entries of that chunk.
+pub(crate) fn sector_bitmap_bat_index(chunk: u64, chunk_ratio: u64) -> u64 {
+    chunk * (chunk_ratio + 1) + chunk_ratio
+}
+
 pub(crate) fn calc_chunk_ratio(sector_size: SectorSize, block_size: usize) -> u64 {
     ((2_u64.pow(23)) * sector_size as u64) / block_size as u64
 }
@@ -86,7 +185,8 @@ pub(crate) fn calc_total_bat_entries_fixed_dynamic(
     payload_blocks_count: u64,
     chunk_ratio: u64,
 ) -> u64 {
-    ((payload_blocks_count - 1) as f64 / chunk_ratio as f64).floor() as u64 + payload_blocks_count
+    (payload_blocks_count.saturating_sub(1) as f64 / chunk_ratio as f64).floor() as u64
+        + payload_blocks_count
 }
 
 pub(crate) fn calc_total_bat_entries_differencing(
@@ -105,4 +205,99 @@ mod tests {
     fn ceil_correctly() {
         assert_eq!(4, calc_payload_blocks_count(10, 3))
     }
+
+    #[test]
+    fn bat_entry_round_trips_through_bits() {
+        let entry = BatEntry::new(BatEntryState::PartiallyPresent, 0xABCDE);
+        let bits = entry.to_bits();
+        assert_eq!(7 | (0xABCDE << 20), bits);
+
+        let decoded = BatEntry::deserialize(&mut std::io::Cursor::new(bits.to_le_bytes())).unwrap();
+        assert_eq!(BatEntryState::PartiallyPresent, decoded.state());
+        assert_eq!(0xABCDE, decoded.file_offset_mb);
+    }
+
+    #[test]
+    fn reserved_bat_states_rejected() {
+        for state in [4_u64, 5] {
+            let bits = state | (4 << 20);
+            assert!(matches!(
+                BatEntry::deserialize(&mut std::io::Cursor::new(bits.to_le_bytes())),
+                Err(VhdxError::InvalidBatState(s)) if s as u64 == state
+            ));
+        }
+
+        let entry = BatEntry::deserialize(&mut std::io::Cursor::new([0; 8])).unwrap();
+        assert_eq!(BatEntryState::NotPresent, entry.state());
+    }
+
+    #[test]
+    fn payload_index_skips_sector_bitmap_entries() {
+        assert_eq!(0, payload_bat_index(0, 2048));
+        assert_eq!(2047, payload_bat_index(2047, 2048));
+        assert_eq!(2049, payload_bat_index(2048, 2048));
+    }
+
+    #[test]
+    fn sector_bitmap_bit_order() {
+        // Sectors 0, 3 and 9 present
+        let mut bitmap = SectorBitmap::from_bytes(vec![0b0000_1001, 0b0000_0010]);
+        let present: Vec<u64> = (0..16).filter(|s| bitmap.is_present(*s)).collect();
+        assert_eq!(vec![0, 3, 9], present);
+        assert!(!bitmap.is_present(16));
+
+        bitmap.set_present(7, true);
+        bitmap.set_present(0, false);
+        bitmap.set_present(16, true);
+        assert_eq!(&[0b1000_1000, 0b0000_0010], bitmap.as_bytes());
+    }
+
+    #[test]
+    fn sector_bitmap_block_deserializes() {
+        let mut block = vec![0; SectorBitmap::SIZE + 8];
+        block[8] = 0b0000_0101;
+        block[8 + SectorBitmap::SIZE - 1] = 0b1000_0000;
+        let mut reader = std::io::Cursor::new(block);
+        reader.set_position(8);
+
+        let bitmap = SectorBitmap::deserialize(&mut reader).unwrap();
+        assert_eq!(SectorBitmap::SIZE, bitmap.as_bytes().len());
+        let present: Vec<u64> = (0..16).filter(|s| bitmap.is_present(*s)).collect();
+        assert_eq!(vec![0, 2], present);
+        let last = SectorBitmap::SIZE as u64 * 8 - 1;
+        assert!(!bitmap.is_present(last - 1));
+        assert!(bitmap.is_present(last));
+        assert!(!bitmap.is_present(last + 1));
+    }
+
+    #[test]
+    fn truncated_sector_bitmap_block() {
+        let mut reader = std::io::Cursor::new(vec![0; 4096]);
+        assert!(matches!(
+            SectorBitmap::deserialize(&mut reader),
+            Err(VhdxError::UnexpectedEof {
+                structure: "Sector Bitmap",
+                offset: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn chunk_ratio_of_one() {
+        // P0 S0 P1 S1 P2, the trailing sector bitmap entry is only counted for differencing disks
+        let kinds: Vec<bool> = (0..5).map(|i| is_sector_bitmap_index(i, 1)).collect();
+        assert_eq!(vec![false, true, false, true, false], kinds);
+
+        let payload: Vec<u64> = (0..3).map(|b| payload_bat_index(b, 1)).collect();
+        assert_eq!(vec![0, 2, 4], payload);
+        assert!(payload.iter().all(|i| !is_sector_bitmap_index(*i, 1)));
+        assert_eq!(
+            vec![1, 3],
+            vec![sector_bitmap_bat_index(0, 1), sector_bitmap_bat_index(1, 1)]
+        );
+
+        assert_eq!(5, calc_total_bat_entries_fixed_dynamic(3, 1));
+        assert_eq!(1, calc_total_bat_entries_fixed_dynamic(1, 1));
+        assert_eq!(6, calc_total_bat_entries_differencing(3, 1));
+    }
 }
@@ -5,6 +5,7 @@ use nom::{
     ErrorConvert,
 };
 use thiserror::Error;
+use uuid::Uuid;
 
 use crate::Signature;
 
@@ -24,9 +25,15 @@ pub enum VhdxError {
     #[error("Missing region in Region Table: {0}")]
     MissingKnownRegion(&'static str),
 
-    #[error("Signature validation failed expected: {0:?}, got: {1:?}")]
+    #[error("Signature validation failed expected: {0}, got: {1}")]
     SignatureError(Signature, Signature),
 
+    #[error("Log descriptor signature is neither desc nor zero: {0}")]
+    UnexpectedDescriptorSignature(Signature),
+
+    #[error("Unknown metadata item marked as required: {0}")]
+    UnknownRequiredMetadataItem(Uuid),
+
     #[error("Calculate crc doesn't match expected: {0}, got: {1}")]
     Crc32Error(u32, u32),
 
@@ -36,7 +43,10 @@ pub enum VhdxError {
     #[error("VHDX Version error should be 1 got: {0}")]
     VersionError(u16),
 
-    #[error("RegionTable EntryCount must be less than 2047 bytes got: {0} bytes")]
+    #[error("VHDX Log Version error should be 0 got: {0}")]
+    LogVersionError(u16),
+
+    #[error("RegionTable or Metadata EntryCount must be at most 2047 got: {0}")]
     RTEntryCountError(u32),
 
     #[error("{0} value, is not dividable by 1MB: {1}")]
@@ -45,8 +55,127 @@ pub enum VhdxError {
     #[error("{0} value, is not dividable by 4096 (4KB): {1}")]
     NotDivisbleBy4KB(&'static str, u64),
 
+    #[error("{0} not found at its required file offset {1}")]
+    StructureMisplaced(&'static str, u64),
+
+    #[error("Header log length must be a multiple of 1MB got: {0}")]
+    LogLengthError(u32),
+
+    #[error("Header log offset must be a multiple of 1MB got: {0}")]
+    LogOffsetError(u64),
+
+    #[error("{0} region at {1} intrudes into the first 1MB reserved for the headers")]
+    RegionInHeaderArea(&'static str, u64),
+
+    #[error("Region file offset must be at least 1MB got: {0}")]
+    RegionOffsetError(u64),
+
+    #[error("Required metadata item missing: {0}")]
+    MissingMetadataEntry(&'static str),
+
+    #[error("Metadata entry offset {0} points into the metadata table header")]
+    MetadataEntryOffsetInvalid(usize),
+
+    #[error("{0} reserved field must be zero")]
+    ReservedNotZero(&'static str),
+
     #[error("{0} number is not allowed to be zero")]
     NotAllowedToBeZero(&'static str),
+
+    #[error("Block size must be a power of two between 1MB and 256MB got: {0}")]
+    BlockSizeError(u64),
+
+    #[error("Sector size must be 512 or 4096 got: {0}")]
+    InvalidSectorSize(u32),
+
+    #[error("Virtual disk size {0} is not a multiple of the logical sector size {1}")]
+    VirtualSizeNotSectorAligned(u64, u64),
+
+    #[error("Region tables are not both valid and identical")]
+    RegionTablesDegraded,
+
+    #[error("Payload blocks {0} and {1} overlap in the file")]
+    BatBlockOverlap(u64, u64),
+
+    #[error("Payload block {0} at file offset {1} extends beyond the end of the file")]
+    BatBlockBeyondEof(u64, u64),
+
+    #[error("BAT entry {0} is beyond the end of the BAT")]
+    BatIndexOutOfRange(u64),
+
+    #[error("BAT entry state {0} is reserved")]
+    InvalidBatState(u8),
+
+    #[error("BAT entry {0} can not be encoded with the given state and file offset")]
+    InvalidBatEntry(u64),
+
+    #[error("Logical sector {0} is beyond the end of the virtual disk")]
+    LbaOutOfRange(u64),
+
+    #[error("Range at offset {0} with length {1} exceeds the virtual disk size")]
+    RangeOutOfBounds(u64, u64),
+
+    #[error("File is {actual} bytes but the log guarantees at least {flushed} bytes were flushed")]
+    FileTruncatedBelowFlushed { flushed: u64, actual: u64 },
+
+    #[error("File of {size} bytes is too small to be a VHDX image")]
+    FileTooSmall { size: u64 },
+
+    #[error("Log region ends at {end} but the file is only {file_size} bytes")]
+    LogRegionBeyondEof { end: u64, file_size: u64 },
+
+    #[error("{structure} at offset {offset} extends beyond the end of the file")]
+    UnexpectedEof {
+        structure: &'static str,
+        offset: u64,
+    },
+
+    #[error("Image was opened read-only")]
+    ReadOnly,
+
+    #[error("Log entry of {0} bytes does not fit in the log")]
+    LogFull(u64),
+
+    #[error(
+        "Log descriptor writing {length} bytes at {file_offset} is misaligned or outside the file"
+    )]
+    InvalidLogDescriptor { file_offset: u64, length: u64 },
+
+    #[error("Log entry of {length} bytes is shorter than the {required} bytes of its sectors")]
+    LogEntryTooShort { length: u64, required: u64 },
+
+    #[error("Differencing disk has no usable parent locator")]
+    MissingParentLocator,
+
+    #[error("Parent linkage mismatch expected: {expected}, found: {found}")]
+    ParentLinkageMismatch { expected: Uuid, found: Uuid },
+
+    #[error("Disk is not differencing or its parent was not opened")]
+    NoParent,
+
+    #[error("Differencing chain is deeper than {0} disks")]
+    ChainTooDeep(usize),
+
+    #[error("Log entry claims {0} descriptors, more than fit in the descriptor sector")]
+    TooManyDescriptors(u32),
+
+    #[error("Log sequence number mismatch expected: {expected}, found: {found}")]
+    LogSequenceMismatch { expected: u64, found: u64 },
+
+    #[error("Log entry guid mismatch expected: {expected}, found: {found}")]
+    LogGuidMismatch { expected: Uuid, found: Uuid },
+}
+
+impl VhdxError {
+    // Names the structure that was being read when the input ended, any other error is kept
+    pub(crate) fn at_structure(self, structure: &'static str, offset: u64) -> Self {
+        match self {
+            VhdxError::IoError(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                VhdxError::UnexpectedEof { structure, offset }
+            }
+            other => other,
+        }
+    }
 }
 
 impl From<VhdxParseError<&[u8]>> for VhdxError {
@@ -60,7 +189,9 @@ impl From<nom::Err<VhdxParseError<&[u8]>>> for VhdxError {
         match value {
             nom::Err::Error(v) => v.into(),
             nom::Err::Failure(v) => v.into(),
-            nom::Err::Incomplete(_) => panic!("No support for streaming parsers"),
+            nom::Err::Incomplete(_) => {
+                VhdxError::ParseError("No support for streaming parsers".to_string())
+            }
         }
     }
 }
@@ -75,6 +206,9 @@ pub enum VhdxParseError<I> {
 
     #[error("Unknown signature detected")]
     UnknownSignature,
+
+    #[error(transparent)]
+    Utf16(#[from] std::string::FromUtf16Error),
 }
 
 impl<I> ParseError<I> for VhdxParseError<I> {
@@ -93,12 +227,23 @@ impl<I> FromExternalError<I, uuid::Error> for VhdxParseError<I> {
     }
 }
 
+impl<I> FromExternalError<I, std::string::FromUtf16Error> for VhdxParseError<I> {
+    fn from_external_error(
+        _input: I,
+        _kind: nom::error::ErrorKind,
+        e: std::string::FromUtf16Error,
+    ) -> Self {
+        VhdxParseError::Utf16(e)
+    }
+}
+
 impl<I> ErrorConvert<VhdxParseError<I>> for VhdxParseError<(I, usize)> {
     fn convert(self) -> VhdxParseError<I> {
         match self {
             VhdxParseError::Uuid(e) => VhdxParseError::Uuid(e),
             VhdxParseError::Nom(e) => VhdxParseError::Nom(make_error(e.input.0, e.code)),
             VhdxParseError::UnknownSignature => VhdxParseError::UnknownSignature,
+            VhdxParseError::Utf16(e) => VhdxParseError::Utf16(e),
         }
     }
 }
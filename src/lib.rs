@@ -1,15 +1,24 @@
 use error::VhdxError;
-use std::io::{Read, Seek};
+use std::io::{Cursor, Read, Seek, Write};
+use vhdx::Vhdx;
 
 pub mod bat;
 pub mod bits_parsers;
 pub mod error;
 pub mod log;
 pub mod meta_data;
+pub mod metrics;
 pub mod parse_utils;
+pub mod reader;
 pub mod vhdx;
 pub mod vhdx_header;
 
+// Parses a complete VHDX image held in memory. Meant as the entry point for fuzzing, arbitrary
+// input must result in an error and never in a panic.
+pub fn parse_vhdx(bytes: &[u8]) -> Result<Vhdx<Cursor<&[u8]>>, VhdxError> {
+    Vhdx::from_reader(Cursor::new(bytes))
+}
+
 pub trait DeSerialise<T> {
     type Item;
 
@@ -18,9 +27,53 @@ pub trait DeSerialise<T> {
         T: Read + Seek;
 }
 
+pub trait Serialise {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), VhdxError>;
+}
+
 pub trait Crc32 {
     fn crc32(&self) -> u32;
     fn crc32_from_digest(&self, digest: &mut crc::Digest<u32>);
+
+    // VHDX mandates CRC-32C, but some foreign tools checksum with a different variant. Hashing
+    // the same bytes with another algorithm helps telling which one produced a stored checksum.
+    fn crc32_with(&self, algorithm: &'static crc::Algorithm<u32>) -> u32 {
+        let crc = crc::Crc::<u32>::new(algorithm);
+        let mut digest = crc.digest();
+        self.crc32_from_digest(&mut digest);
+        digest.finalize()
+    }
+}
+
+// Chunk size used when hashing straight from a reader
+const CRC_STREAM_CHUNK: usize = 8 * 1024;
+
+// Computes the CRC-32C over the next `len` bytes of the reader without buffering them all, so
+// large structures like log entries can be verified with a flat memory footprint.
+pub fn crc32c_stream<R: Read>(reader: &mut R, len: u64) -> Result<u32, VhdxError> {
+    let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI);
+    let mut digest = crc.digest();
+    crc32c_stream_into(reader, len, &mut digest)?;
+    Ok(digest.finalize())
+}
+
+// Same as crc32c_stream but continues a digest that already covers the preceding bytes
+pub(crate) fn crc32c_stream_into<R: Read>(
+    reader: &mut R,
+    len: u64,
+    digest: &mut crc::Digest<u32>,
+) -> Result<(), VhdxError> {
+    let mut buffer = [0; CRC_STREAM_CHUNK];
+    let mut remaining = len;
+
+    while remaining > 0 {
+        let chunk = remaining.min(CRC_STREAM_CHUNK as u64) as usize;
+        reader.read_exact(&mut buffer[..chunk])?;
+        digest.update(&buffer[..chunk]);
+        remaining -= chunk as u64;
+    }
+
+    Ok(())
 }
 
 pub trait Validation {
@@ -39,3 +92,143 @@ pub enum Signature {
     MetaData,
     Unknown(Vec<u8>),
 }
+
+impl Signature {
+    // The bytes stored on disk for a known signature, None for an unknown one
+    pub fn as_bytes(&self) -> Option<&'static [u8]> {
+        match self {
+            Signature::Vhdxfile => Some(b"vhdxfile"),
+            Signature::Head => Some(b"head"),
+            Signature::Regi => Some(b"regi"),
+            Signature::Loge => Some(b"loge"),
+            Signature::Zero => Some(b"zero"),
+            Signature::Data => Some(b"data"),
+            Signature::Desc => Some(b"desc"),
+            Signature::MetaData => Some(b"metadata"),
+            Signature::Unknown(_) => None,
+        }
+    }
+}
+
+// Prints the ASCII form, bytes outside of printable ASCII are escaped
+impl std::fmt::Display for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Signature::Unknown(bytes) => write!(f, "{}", bytes.escape_ascii()),
+            known => write!(f, "{}", known.as_bytes().unwrap_or_default().escape_ascii()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: usize = 1024 * 1024;
+    const META_DATA: usize = 2 * MB;
+    const LOG: usize = MB;
+
+    fn patched_fixture(offset: usize, bytes: &[u8]) -> Vec<u8> {
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+        image
+    }
+
+    #[test]
+    fn signature_bytes_match_parsers() {
+        use crate::parse_utils::{t_sign_u32, t_sign_u64};
+
+        for signature in [
+            Signature::Head,
+            Signature::Regi,
+            Signature::Loge,
+            Signature::Zero,
+            Signature::Data,
+            Signature::Desc,
+        ] {
+            assert_eq!(
+                signature,
+                t_sign_u32(signature.as_bytes().unwrap()).unwrap().1
+            );
+        }
+        for signature in [Signature::Vhdxfile, Signature::MetaData] {
+            assert_eq!(
+                signature,
+                t_sign_u64(signature.as_bytes().unwrap()).unwrap().1
+            );
+        }
+        assert_eq!(None, Signature::Unknown(b"abcd".to_vec()).as_bytes());
+    }
+
+    #[test]
+    fn signature_display() {
+        assert_eq!("head", Signature::Head.to_string());
+        assert_eq!("metadata", Signature::MetaData.to_string());
+        assert_eq!(
+            "ab\\x00\\xff",
+            Signature::Unknown(vec![b'a', b'b', 0, 0xFF]).to_string()
+        );
+        assert_eq!(
+            "Signature validation failed expected: head, got: regi",
+            VhdxError::SignatureError(Signature::Head, Signature::Regi).to_string()
+        );
+    }
+
+    #[test]
+    fn crc32c_stream_matches_buffered() {
+        let bytes: Vec<u8> = (0..3 * CRC_STREAM_CHUNK + 123).map(|i| i as u8).collect();
+        let buffered = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI).checksum(&bytes);
+        let streamed = crc32c_stream(&mut Cursor::new(&bytes), bytes.len() as u64).unwrap();
+        assert_eq!(buffered, streamed);
+    }
+
+    #[test]
+    fn crc32c_stream_matches_header_checksum() {
+        const HEADER_2: usize = 128 * 1024;
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut header = image[HEADER_2..HEADER_2 + 4096].to_vec();
+        let stored = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        header[4..8].fill(0);
+
+        let streamed = crc32c_stream(&mut Cursor::new(&header), 4096).unwrap();
+        assert_eq!(stored, streamed);
+    }
+
+    #[test]
+    fn crc32c_stream_fails_on_short_reader() {
+        let bytes = [0u8; 16];
+        assert!(crc32c_stream(&mut Cursor::new(&bytes), 32).is_err());
+    }
+
+    #[test]
+    fn parse_vhdx_accepts_fixture() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        assert!(parse_vhdx(&image).is_ok());
+    }
+
+    // Inputs that used to crash the parser, they must all be rejected with an error
+    #[test]
+    fn parse_vhdx_regression_corpus() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        let corpus = [
+            Vec::new(),
+            image[..300 * 1024].to_vec(),
+            // Unpaired UTF-16 surrogate in the creator
+            patched_fixture(8, &[0x00, 0xD8, 0x41, 0x00]),
+            // Unknown descriptor signature in the second log entry
+            patched_fixture(LOG + 4096 + 64, b"xxxx"),
+            // Descriptor count way beyond what fits in the entry
+            patched_fixture(LOG + 4096 + 24, &[0xFF; 4]),
+            // Unknown metadata item
+            patched_fixture(META_DATA + 32, &[0xAB; 16]),
+            // Block size of zero
+            patched_fixture(META_DATA + 0x10000, &[0; 4]),
+            // Logical sector size of 1024
+            patched_fixture(META_DATA + 0x10010, &1024_u32.to_le_bytes()),
+        ];
+
+        corpus
+            .iter()
+            .for_each(|input| assert!(parse_vhdx(input).is_err()));
+    }
+}
@@ -1,8 +1,8 @@
 use crc::{Crc, CRC_32_ISCSI};
 use nom::Finish;
 use std::{
-    io::{Read, Seek},
-    iter, usize,
+    io::{Read, Seek, Write},
+    iter,
 };
 use uuid::Uuid;
 
@@ -14,10 +14,11 @@ use nom::{
 };
 
 use crate::{
+    crc32c_stream_into,
     error::VhdxError,
     parse_utils::{t_guid, t_sign_u32, t_u32, t_u64},
     vhdx::Vhdx,
-    Crc32, DeSerialise, Signature, Validation,
+    Crc32, DeSerialise, Serialise, Signature, Validation,
 };
 
 #[derive(Debug)]
@@ -27,36 +28,226 @@ pub struct Log {
 }
 
 impl Log {
-    pub(crate) fn new(log_entries: Vec<LogEntry>) -> Self {
-        let entries = log_entries.clone();
-        Self {
+    pub(crate) fn new(log_entries: Vec<LogEntry>) -> Result<Self, VhdxError> {
+        let log_sequence = Vhdx::try_get_log_sequence(&log_entries)?;
+        Ok(Self {
             log_entries,
-            log_sequence: Vhdx::try_get_log_sequence(&entries).unwrap(),
-        }
+            log_sequence,
+        })
     }
 }
 
+// Overview of the log for tooling, offsets are relative to the start of the log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogSummary {
+    pub log_guid: Uuid,
+    pub entries: usize,
+    pub active_sequence_number: u64,
+    pub head_offset: u64,
+    pub tail_offset: u64,
+    // Entries written with the log guid of the header that pass validation
+    pub valid_entries: usize,
+    pub invalid_entries: usize,
+    // Number of descriptors of every parsed entry in log order
+    pub descriptor_counts: Vec<u32>,
+}
+
+// Outcome of replaying the active log, see Vhdx::replay_log
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayResult {
+    // Number of data and zero descriptors written to the file
+    pub blocks_written: u64,
+    pub bytes_written: u64,
+    // Header guids after the replay, the log guid is nil since the log is no longer in use
+    pub new_file_write_guid: Uuid,
+    pub new_log_guid: Uuid,
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct LogEntry {
     pub(crate) header: LogHeader,
     descriptors: Vec<Descriptor>,
+    // Where the entry was found relative to the start of the log
+    pub(crate) log_offset: u64,
+    // CRC-32C over the entry_length bytes as stored, with the checksum field taken as zero.
+    // Computed while reading, entries built in memory are hashed from their contents instead.
+    stored_crc: Option<u32>,
 }
 
 impl LogEntry {
     const SECTOR_SIZE: usize = 4096;
     const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+    // The header and the descriptors have to share the first sector of the entry
+    const MAX_DESCRIPTORS: u32 =
+        ((LogEntry::SECTOR_SIZE - LogHeader::SIZE) / Descriptor::SIZE) as u32;
 
     fn new(header: LogHeader, descriptors: Vec<Descriptor>) -> Self {
         Self {
             header,
             descriptors,
+            log_offset: 0,
+            stored_crc: None,
+        }
+    }
+
+    // Builds a complete entry around the descriptors, the descriptors take on the sequence number
+    // of the entry and the entry length and checksum are derived from the contents.
+    pub(crate) fn create(
+        seq_number: u64,
+        tail: u32,
+        log_guid: Uuid,
+        flushed_file_offset: u64,
+        last_file_offset: u64,
+        mut descriptors: Vec<Descriptor>,
+    ) -> LogEntry {
+        descriptors
+            .iter_mut()
+            .for_each(|descriptor| descriptor.set_sequence_number(seq_number));
+
+        let entry_length = LogEntry::length_for(&descriptors);
+        let header = LogHeader::new(
+            Signature::Loge,
+            0,
+            entry_length as u32,
+            tail,
+            seq_number,
+            descriptors.len() as u32,
+            log_guid,
+            flushed_file_offset,
+            last_file_offset,
+        );
+        let mut entry = LogEntry::new(header, descriptors);
+        entry.header.checksum = LogEntry::CRC.checksum(&entry.to_bytes());
+        entry
+    }
+
+    // Length of an entry holding the descriptors, the header and descriptors are padded to a
+    // sector and every data descriptor adds a data sector.
+    pub(crate) fn length_for(descriptors: &[Descriptor]) -> u64 {
+        let descriptor_sectors = (64 + descriptors.len() * 32).div_ceil(LogEntry::SECTOR_SIZE);
+        let data_sectors = descriptors
+            .iter()
+            .filter(|descriptor| matches!(descriptor, Descriptor::Data(_)))
+            .count();
+        ((descriptor_sectors + data_sectors) * LogEntry::SECTOR_SIZE) as u64
+    }
+
+    pub fn sequence_number(&self) -> u64 {
+        self.header.seq_number
+    }
+
+    pub fn entry_length(&self) -> u32 {
+        self.header.entry_length
+    }
+
+    // On disk layout: header and descriptors padded to a sector followed by one data sector per
+    // data descriptor.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.header.entry_length as usize);
+        bytes.extend_from_slice(&self.header.to_bytes());
+        for descriptor in &self.descriptors {
+            bytes.extend_from_slice(&descriptor.to_bytes());
+        }
+        bytes.resize(
+            bytes.len().div_ceil(LogEntry::SECTOR_SIZE) * LogEntry::SECTOR_SIZE,
+            0,
+        );
+
+        for descriptor in &self.descriptors {
+            if let Descriptor::Data(DataDesc {
+                data_sector: Some(sector),
+                ..
+            }) = descriptor
+            {
+                bytes.extend_from_slice(&sector.to_bytes());
+            }
         }
+        bytes
+    }
+
+    // Applies the descriptors of the entry in order to a buffer holding the file contents
+    // starting at the given file offset, as a replay would write them.
+    pub(crate) fn apply_to(&self, file_offset: u64, buffer: &mut [u8]) {
+        self.descriptors
+            .iter()
+            .for_each(|descriptor| descriptor.apply_to(file_offset, buffer));
+    }
+
+    // Writes every descriptor of the entry to its final location in the file, in order
+    pub(crate) fn replay<W: Write + Seek>(
+        &self,
+        writer: &mut W,
+        result: &mut ReplayResult,
+    ) -> Result<(), VhdxError> {
+        for descriptor in &self.descriptors {
+            descriptor.replay(writer)?;
+            result.blocks_written += 1;
+            result.bytes_written = result
+                .bytes_written
+                .saturating_add(descriptor.file_range().1);
+        }
+        Ok(())
+    }
+
+    // Whether any data or zero descriptor of this entry writes to the given file offset
+    pub fn touches(&self, file_offset: u64) -> bool {
+        self.descriptors.iter().any(|descriptor| {
+            let (start, length) = descriptor.file_range();
+            file_offset >= start && file_offset - start < length
+        })
     }
 }
 
+// Whether the entry was written completely, the log guid is not checked here since it depends on
+// the header the entry is replayed against.
 impl Validation for LogEntry {
     fn validate(&self) -> Result<(), VhdxError> {
+        self.header.validate()?;
+
+        let crc = self.crc32();
+        if crc != self.header.checksum {
+            return Err(VhdxError::Crc32Error(self.header.checksum, crc));
+        }
+
+        // Descriptors and data sectors carry the sequence number of the entry they belong to, a
+        // mismatch means a sector of an older entry was left behind
+        let expected = self.header.seq_number;
+        for descriptor in &self.descriptors {
+            let (seq_number, sector_seq_number) = match descriptor {
+                Descriptor::Zero(z) => (z.seq_number, None),
+                Descriptor::Data(d) => (
+                    d.seq_number,
+                    d.data_sector.as_ref().map(DataSector::sequence_number),
+                ),
+            };
+            for found in iter::once(seq_number).chain(sector_seq_number) {
+                if found != expected {
+                    return Err(VhdxError::LogSequenceMismatch { expected, found });
+                }
+            }
+        }
+
+        // Replaying writes every descriptor to the file, a range that is misaligned, lies past
+        // the file size the entry was written for or touches the header section other than the
+        // region tables can only come from a corrupt or crafted entry
+        for descriptor in &self.descriptors {
+            let (file_offset, length) = descriptor.file_range();
+            let sector = LogEntry::SECTOR_SIZE as u64;
+            let placed = file_offset.checked_add(length).is_some_and(|end| {
+                let region_tables = 192 * Vhdx::KB..=320 * Vhdx::KB;
+                let header_section = file_offset >= Vhdx::MB
+                    || region_tables.contains(&file_offset) && region_tables.contains(&end);
+                header_section && end <= self.header.last_file_offset
+            });
+            if !file_offset.is_multiple_of(sector) || !length.is_multiple_of(sector) || !placed {
+                return Err(VhdxError::InvalidLogDescriptor {
+                    file_offset,
+                    length,
+                });
+            }
+        }
+
         Ok(())
     }
 }
@@ -68,49 +259,103 @@ impl<T> DeSerialise<T> for LogEntry {
     where
         T: Read + Seek,
     {
+        let offset = reader.stream_position()?;
+        LogEntry::read_from(reader).map_err(|e| e.at_structure("Log Entry", offset))
+    }
+}
+
+impl LogEntry {
+    fn read_from<T: Read + Seek>(reader: &mut T) -> Result<LogEntry, VhdxError> {
         let start_pos = reader.stream_position()?;
 
         let header = LogHeader::deserialize(reader)?;
-        let mut descriptors = Vec::with_capacity(header.descript_count as usize);
-        if header.descript_count != 0 {
-            for _ in 0..header.descript_count {
-                let mut buffer = [0; 4];
-                reader.read_exact(&mut buffer)?;
-                let mut peeker = peek(t_sign_u32);
-                let (_, signature) = peeker(&buffer)?;
-                reader.seek(std::io::SeekFrom::Current(-4))?;
-                let desc = match signature {
-                    Signature::Desc => Descriptor::Data(DataDesc::deserialize(reader)?),
-                    Signature::Zero => Descriptor::Zero(ZeroDesc::deserialize(reader)?),
-                    _ => panic!("Fix this error"),
-                };
-                descriptors.push(desc);
-            }
+        if header.descript_count > LogEntry::MAX_DESCRIPTORS {
+            return Err(VhdxError::TooManyDescriptors(header.descript_count));
+        }
+        let mut descriptors = Vec::new();
+        for _ in 0..header.descript_count {
+            let mut buffer = [0; 4];
+            reader.read_exact(&mut buffer)?;
+            let mut peeker = peek(t_sign_u32);
+            let (_, signature) = peeker(&buffer)?;
+            reader.seek(std::io::SeekFrom::Current(-4))?;
+            let desc = match signature {
+                Signature::Desc => Descriptor::Data(DataDesc::deserialize(reader)?),
+                Signature::Zero => Descriptor::Zero(ZeroDesc::deserialize(reader)?),
+                signature => return Err(VhdxError::UnexpectedDescriptorSignature(signature)),
+            };
+            descriptors.push(desc);
         }
 
         let current_pos = reader.stream_position()?;
-        let offset = LogEntry::SECTOR_SIZE as u64 - (current_pos - start_pos);
+        let offset = (LogEntry::SECTOR_SIZE as u64)
+            .checked_sub(current_pos - start_pos)
+            .ok_or_else(|| {
+                VhdxError::ParseError("Log descriptors do not fit in one sector".to_string())
+            })?;
         reader.seek(std::io::SeekFrom::Current(offset as i64))?;
 
-        descriptors.iter_mut().for_each(|v| match v {
-            Descriptor::Data(desc) => {
-                let d_sector = DataSector::deserialize(reader).unwrap();
-                desc.data_sector = Some(d_sector);
+        // Only data descriptors are followed by a data sector
+        for descriptor in descriptors.iter_mut() {
+            if let Descriptor::Data(desc) = descriptor {
+                desc.data_sector = Some(DataSector::deserialize(reader)?);
             }
-            Descriptor::Zero(_) => todo!(),
-        });
-        let log_entry = LogEntry::new(header, descriptors);
+        }
+
+        // The next entry starts entry_length bytes further, which has to span the descriptor
+        // sector as well as every data sector of the entry
+        let entry_length = header.entry_length as u64;
+        if entry_length == 0 || !entry_length.is_multiple_of(LogEntry::SECTOR_SIZE as u64) {
+            return Err(VhdxError::NotDivisbleBy4KB(
+                "Log Entry Length",
+                entry_length,
+            ));
+        }
+        let required = LogEntry::length_for(&descriptors);
+        if entry_length < required {
+            return Err(VhdxError::LogEntryTooShort {
+                length: entry_length,
+                required,
+            });
+        }
+
+        // The checksum covers everything up to the entry length, including whatever is stored
+        // after the data sectors, so it is hashed from the file rather than the parsed parts
+        reader.seek(std::io::SeekFrom::Start(start_pos))?;
+        let mut digest = LogEntry::CRC.digest();
+        let mut signature = [0; 4];
+        reader.read_exact(&mut signature)?;
+        digest.update(&signature);
+        reader.seek(std::io::SeekFrom::Current(4))?;
+        digest.update(&[0; 4]);
+        crc32c_stream_into(reader, entry_length - 8, &mut digest)?;
+
+        let mut log_entry = LogEntry::new(header, descriptors);
+        log_entry.stored_crc = Some(digest.finalize());
         Ok(log_entry)
     }
 }
 
+impl Serialise for LogEntry {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), VhdxError> {
+        writer.write_all(&self.to_bytes())?;
+        Ok(())
+    }
+}
+
 impl Crc32 for LogEntry {
+    // An entry read from a file is hashed over all entry_length bytes as stored, including any
+    // space past its data sectors. Other entries are hashed from their parsed contents.
     fn crc32(&self) -> u32 {
+        if let Some(crc) = self.stored_crc {
+            return crc;
+        }
         let mut digest = LogEntry::CRC.digest();
         self.crc32_from_digest(&mut digest);
         digest.finalize()
     }
 
+    // Hashes the parsed contents, the descriptor sector and the data sectors
     fn crc32_from_digest(&self, digest: &mut crc::Digest<u32>) {
         self.header.crc32_from_digest(digest);
         self.descriptors.crc32_from_digest(digest);
@@ -119,7 +364,9 @@ impl Crc32 for LogEntry {
 
 impl Crc32 for Vec<Descriptor> {
     fn crc32(&self) -> u32 {
-        todo!()
+        let mut digest = Descriptor::CRC.digest();
+        self.crc32_from_digest(&mut digest);
+        digest.finalize()
     }
 
     fn crc32_from_digest(&self, digest: &mut crc::Digest<u32>) {
@@ -127,9 +374,8 @@ impl Crc32 for Vec<Descriptor> {
             desc.crc32_from_digest(digest);
         });
 
-        let zeros: Vec<u8> = iter::repeat(0)
-            .take(4096 - ((64 + (self.len() * 32)) % 4096))
-            .collect();
+        let used = (64 + self.len() * 32) % 4096;
+        let zeros: Vec<u8> = iter::repeat_n(0, (4096 - used) % 4096).collect();
         digest.update(&zeros);
 
         self.iter().for_each(|desc| {
@@ -197,7 +443,10 @@ pub struct LogHeader {
 
 impl LogHeader {
     pub const SIGN: &'static [u8] = &[0x6C, 0x6F, 0x67, 0x65];
+    const SIZE: usize = 64;
     const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+    #[allow(clippy::too_many_arguments)]
     fn new(
         signature: Signature,
         checksum: u32,
@@ -223,6 +472,22 @@ impl LogHeader {
     }
 }
 
+impl LogHeader {
+    fn to_bytes(&self) -> [u8; 64] {
+        let mut bytes = [0; 64];
+        bytes[..4].copy_from_slice(LogHeader::SIGN);
+        bytes[4..8].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.entry_length.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.tail.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.seq_number.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.descript_count.to_le_bytes());
+        bytes[32..48].copy_from_slice(&self.log_guid.to_bytes_le());
+        bytes[48..56].copy_from_slice(&self.flushed_file_offset.to_le_bytes());
+        bytes[56..64].copy_from_slice(&self.last_file_offset.to_le_bytes());
+        bytes
+    }
+}
+
 impl<T> DeSerialise<T> for LogHeader {
     type Item = LogHeader;
 
@@ -297,35 +562,31 @@ impl Validation for LogHeader {
             ));
         }
 
-        // TODO: Calc checksum
+        // The checksum covers the whole entry and is verified by LogEntry::validate
 
-        if self.entry_length as u64 % (Vhdx::KB * 4) != 0 {
-            return Err(VhdxError::NotDivisbleByMB(
+        if !(self.entry_length as u64).is_multiple_of(Vhdx::KB * 4) {
+            return Err(VhdxError::NotDivisbleBy4KB(
                 "Log Entry Length",
                 self.entry_length as u64,
             ));
         }
 
-        if self.tail as u64 % (Vhdx::KB * 4) != 0 {
-            return Err(VhdxError::NotDivisbleByMB("Log Tail", self.tail as u64));
+        if !(self.tail as u64).is_multiple_of(Vhdx::KB * 4) {
+            return Err(VhdxError::NotDivisbleBy4KB("Log Tail", self.tail as u64));
         }
 
         if self.seq_number == 0 {
             return Err(VhdxError::NotAllowedToBeZero("Log Sequence Number"));
         }
 
-        if self.descript_count == 0 {
-            return Err(VhdxError::NotAllowedToBeZero("Log Description Count"));
-        }
-
-        if self.flushed_file_offset % Vhdx::MB != 0 {
+        if !self.flushed_file_offset.is_multiple_of(Vhdx::MB) {
             return Err(VhdxError::NotDivisbleByMB(
                 "Flushed File Offset",
                 self.flushed_file_offset,
             ));
         }
 
-        if self.last_file_offset % Vhdx::MB != 0 {
+        if !self.last_file_offset.is_multiple_of(Vhdx::MB) {
             return Err(VhdxError::NotDivisbleByMB(
                 "Last File Offset",
                 self.last_file_offset,
@@ -338,17 +599,131 @@ impl Validation for LogHeader {
 
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
-pub(crate) enum Descriptor {
+pub enum Descriptor {
     Zero(ZeroDesc),
     Data(DataDesc),
 }
 
 impl Descriptor {
+    const SIZE: usize = 32;
     const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+    // Zeroes length bytes (a multiple of 4KB) at the file offset when replayed
+    pub fn zero(file_offset: u64, length: u64) -> Descriptor {
+        Descriptor::Zero(ZeroDesc {
+            signature: Signature::Zero,
+            zero_length: length,
+            file_offset,
+            seq_number: 0,
+        })
+    }
+
+    // Writes the 4KB sector at the file offset when replayed, the first 8 and last 4 bytes are
+    // kept in the descriptor and the rest goes into the data sector.
+    pub fn data(file_offset: u64, sector: &[u8; 4096]) -> Descriptor {
+        Descriptor::Data(DataDesc {
+            signature: Signature::Desc,
+            trailing_bytes: sector[4092..].to_vec(),
+            leading_bytes: sector[..8].to_vec(),
+            file_offset,
+            seq_number: 0,
+            data_sector: Some(DataSector::new(Signature::Data, 0, &sector[8..4092], 0)),
+        })
+    }
+
+    fn set_sequence_number(&mut self, seq_number: u64) {
+        match self {
+            Descriptor::Zero(z) => z.seq_number = seq_number,
+            Descriptor::Data(d) => {
+                d.seq_number = seq_number;
+                if let Some(sector) = d.data_sector.as_mut() {
+                    sector.seq_high = (seq_number >> 32) as u32;
+                    sector.seq_low = seq_number as u32;
+                }
+            }
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        match self {
+            Descriptor::Zero(z) => {
+                bytes[..4].copy_from_slice(ZeroDesc::SIGN);
+                bytes[8..16].copy_from_slice(&z.zero_length.to_le_bytes());
+                bytes[16..24].copy_from_slice(&z.file_offset.to_le_bytes());
+                bytes[24..32].copy_from_slice(&z.seq_number.to_le_bytes());
+            }
+            Descriptor::Data(d) => {
+                bytes[..4].copy_from_slice(DataDesc::SIGN);
+                bytes[4..8].copy_from_slice(&d.trailing_bytes);
+                bytes[8..16].copy_from_slice(&d.leading_bytes);
+                bytes[16..24].copy_from_slice(&d.file_offset.to_le_bytes());
+                bytes[24..32].copy_from_slice(&d.seq_number.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    // Writes the part of the descriptor that overlaps the buffer, which holds the file contents
+    // starting at the given file offset.
+    fn apply_to(&self, file_offset: u64, buffer: &mut [u8]) {
+        let (start, length) = self.file_range();
+        let from = start.max(file_offset);
+        let to = start
+            .saturating_add(length)
+            .min(file_offset.saturating_add(buffer.len() as u64));
+        if from >= to {
+            return;
+        }
+        let target = &mut buffer[(from - file_offset) as usize..(to - file_offset) as usize];
+        let source = (from - start) as usize..(to - start) as usize;
+
+        match self {
+            Descriptor::Zero(_) => target.fill(0),
+            Descriptor::Data(d) => {
+                if let Some(bytes) = d.sector_bytes() {
+                    target.copy_from_slice(&bytes[source]);
+                }
+            }
+        }
+    }
+
+    // Writes the descriptor to its final location in the file
+    fn replay<W: Write + Seek>(&self, writer: &mut W) -> Result<(), VhdxError> {
+        let (file_offset, length) = self.file_range();
+        writer.seek(std::io::SeekFrom::Start(file_offset))?;
+        match self {
+            Descriptor::Zero(_) => {
+                let zeros = vec![0; length.min(Vhdx::MB) as usize];
+                let mut remaining = length;
+                while remaining > 0 {
+                    let chunk = remaining.min(zeros.len() as u64);
+                    writer.write_all(&zeros[..chunk as usize])?;
+                    remaining -= chunk;
+                }
+            }
+            Descriptor::Data(d) => {
+                let bytes = d.sector_bytes().ok_or_else(|| {
+                    VhdxError::ParseError("Data descriptor without data sector".to_string())
+                })?;
+                writer.write_all(&bytes)?;
+            }
+        }
+        Ok(())
+    }
+
+    // File offset and length written by the descriptor, a data descriptor always covers a single
+    // 4KB sector.
+    pub(crate) fn file_range(&self) -> (u64, u64) {
+        match self {
+            Descriptor::Zero(z) => (z.file_offset, z.zero_length),
+            Descriptor::Data(d) => (d.file_offset, LogEntry::SECTOR_SIZE as u64),
+        }
+    }
 }
 
 #[derive(Clone)]
-pub(crate) struct ZeroDesc {
+pub struct ZeroDesc {
     // ZeroSignature (4 bytes): MUST be 0x6F72657A ("zero" as ASCII).
     signature: Signature,
 
@@ -364,7 +739,7 @@ pub(crate) struct ZeroDesc {
     seq_number: u64,
 }
 impl ZeroDesc {
-    pub(crate) const SIGN: &'static [u8] = &[0x7A, 0x65, 0x72, 0x67];
+    pub(crate) const SIGN: &'static [u8] = &[0x7A, 0x65, 0x72, 0x6F];
     const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
 }
 
@@ -433,7 +808,7 @@ impl Crc32 for ZeroDesc {
 }
 
 #[derive(Clone)]
-pub(crate) struct DataDesc {
+pub struct DataDesc {
     signature: Signature,
 
     // TrailingBytes (4 bytes): Contains the four trailing bytes that were removed from the
@@ -460,6 +835,18 @@ pub(crate) struct DataDesc {
 impl DataDesc {
     pub(crate) const SIGN: &'static [u8] = &[0x64, 0x65, 0x73, 0x63];
     const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+    // The complete 4KB sector with the leading and trailing bytes restored
+    fn sector_bytes(&self) -> Option<Vec<u8>> {
+        self.data_sector.as_ref().map(|sector| {
+            self.leading_bytes
+                .iter()
+                .chain(sector.data.iter())
+                .chain(self.trailing_bytes.iter())
+                .copied()
+                .collect()
+        })
+    }
 }
 
 impl<T> DeSerialise<T> for DataDesc {
@@ -550,6 +937,15 @@ impl DataSector {
     fn sequence_number(&self) -> u64 {
         ((self.seq_high as u64) << 32) | self.seq_low as u64
     }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(LogEntry::SECTOR_SIZE);
+        bytes.extend_from_slice(DataSector::SIGN);
+        bytes.extend_from_slice(&self.seq_high.to_le_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes.extend_from_slice(&self.seq_low.to_le_bytes());
+        bytes
+    }
 }
 
 impl<T> DeSerialise<T> for DataSector {
@@ -608,10 +1004,17 @@ impl LogSequence {
         self.entries.is_empty()
     }
 
+    // The tail of the head entry has to lie within the sequence, which may wrap around the end
+    // of the circular log
     pub(crate) fn is_valid(&self) -> bool {
         self.head()
             .map(|v| {
-                self.tail_value <= v.header.tail as u64 && self.head_value >= v.header.tail as u64
+                let tail = v.header.tail as u64;
+                if self.tail_value <= self.head_value {
+                    self.tail_value <= tail && tail <= self.head_value
+                } else {
+                    tail >= self.tail_value || tail <= self.head_value
+                }
             })
             .unwrap_or(false)
     }
@@ -647,4 +1050,282 @@ mod tests {
 
         assert_eq!(Signature::Loge, entry_header.signature);
     }
+
+    const MB: u64 = 1024 * 1024;
+
+    fn entry_with(descriptors: Vec<Descriptor>) -> LogEntry {
+        let header = LogHeader {
+            signature: Signature::Loge,
+            checksum: 0,
+            entry_length: 4096,
+            tail: 0,
+            seq_number: 1,
+            descript_count: descriptors.len() as u32,
+            log_guid: Uuid::nil(),
+            flushed_file_offset: 0,
+            last_file_offset: 0,
+        };
+        LogEntry::new(header, descriptors)
+    }
+
+    #[test]
+    fn too_many_descriptors_rejected() {
+        let mut entry = entry_with(Vec::new());
+        entry.header.descript_count = LogEntry::MAX_DESCRIPTORS;
+        let mut bytes = entry.header.to_bytes().to_vec();
+        bytes.resize(4096, 0);
+        // The full descriptor sector is read, the zeroed descriptors fail on their signature
+        assert!(matches!(
+            LogEntry::deserialize(&mut Cursor::new(&bytes)),
+            Err(VhdxError::UnexpectedDescriptorSignature(_))
+        ));
+
+        entry.header.descript_count = LogEntry::MAX_DESCRIPTORS + 1;
+        let mut bytes = entry.header.to_bytes().to_vec();
+        bytes.resize(8192, 0);
+        assert!(matches!(
+            LogEntry::deserialize(&mut Cursor::new(&bytes)),
+            Err(VhdxError::TooManyDescriptors(127))
+        ));
+    }
+
+    #[test]
+    fn entry_crc_spans_entry_length() {
+        let sectors = [[0x11_u8; 4096], [0x22_u8; 4096], [0x33_u8; 4096]];
+        let descriptors = sectors
+            .iter()
+            .enumerate()
+            .map(|(i, sector)| Descriptor::data(MB + i as u64 * 4096, sector))
+            .collect();
+        let mut entry = LogEntry::create(1, 0, Uuid::nil(), 2 * MB, 2 * MB, descriptors);
+        assert_eq!(4 * 4096, entry.entry_length());
+        assert_eq!(entry.header.checksum, entry.crc32());
+
+        // Whatever is stored past the data sectors up to the entry length is part of the checksum
+        entry.header.entry_length += 4096;
+        let mut bytes = entry.to_bytes();
+        bytes[4..8].fill(0);
+        bytes.resize(5 * 4096, 0x5A);
+        let checksum = LogEntry::CRC.checksum(&bytes);
+        bytes[4..8].copy_from_slice(&checksum.to_le_bytes());
+
+        let parsed = LogEntry::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert!(parsed.validate().is_ok());
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let parsed = LogEntry::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert!(matches!(
+            parsed.validate(),
+            Err(VhdxError::Crc32Error(_, _))
+        ));
+    }
+
+    #[test]
+    fn entry_length_must_cover_sectors() {
+        let sector = [0x11_u8; 4096];
+        let entry = LogEntry::create(1, 0, Uuid::nil(), 0, 0, vec![Descriptor::data(0, &sector)]);
+        let mut bytes = entry.to_bytes();
+        bytes[8..12].copy_from_slice(&4096_u32.to_le_bytes());
+        assert!(matches!(
+            LogEntry::deserialize(&mut Cursor::new(&bytes)),
+            Err(VhdxError::LogEntryTooShort {
+                length: 4096,
+                required: 8192
+            })
+        ));
+
+        bytes[8..12].copy_from_slice(&10000_u32.to_le_bytes());
+        assert!(matches!(
+            LogEntry::deserialize(&mut Cursor::new(&bytes)),
+            Err(VhdxError::NotDivisbleBy4KB(_, 10000))
+        ));
+    }
+
+    #[test]
+    fn deserialize_mixed_zero_and_data_descriptors() {
+        let sector = [0x42_u8; 4096];
+        let entry = LogEntry::create(
+            3,
+            0,
+            Uuid::nil(),
+            2 * MB,
+            2 * MB,
+            vec![
+                Descriptor::zero(MB, 8192),
+                Descriptor::data(MB + 8192, &sector),
+            ],
+        );
+        let mut bytes = Vec::new();
+        entry.serialize(&mut bytes).unwrap();
+        // Only the data descriptor is followed by a data sector
+        assert_eq!(2 * 4096, bytes.len());
+
+        let mut reader = Cursor::new(&bytes);
+        let parsed = LogEntry::deserialize(&mut reader).unwrap();
+        assert_eq!(bytes.len() as u64, reader.position());
+        assert!(parsed.validate().is_ok());
+        assert!(matches!(parsed.descriptors[0], Descriptor::Zero(_)));
+
+        let mut buffer = vec![0xFF; 3 * 4096];
+        parsed.apply_to(MB, &mut buffer);
+        assert!(buffer[..8192].iter().all(|b| *b == 0));
+        assert_eq!(&sector[..], &buffer[8192..]);
+    }
+
+    #[test]
+    fn unknown_descriptor_signature_is_error() {
+        let entry = LogEntry::create(3, 0, Uuid::nil(), 0, 0, vec![Descriptor::zero(0, 4096)]);
+        let mut bytes = Vec::new();
+        entry.serialize(&mut bytes).unwrap();
+        bytes[64..68].copy_from_slice(b"xxxx");
+
+        assert!(matches!(
+            LogEntry::deserialize(&mut Cursor::new(&bytes)),
+            Err(VhdxError::UnexpectedDescriptorSignature(
+                Signature::Unknown(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn entry_validation() {
+        let sector = [0x11_u8; 4096];
+        let create = || {
+            LogEntry::create(
+                7,
+                0,
+                Uuid::nil(),
+                2 * MB,
+                2 * MB,
+                vec![
+                    Descriptor::zero(MB, 4096),
+                    Descriptor::data(MB + 4096, &sector),
+                ],
+            )
+        };
+        assert!(create().validate().is_ok());
+        // Entries without descriptors are allowed
+        assert!(LogEntry::create(7, 0, Uuid::nil(), 0, 0, Vec::new())
+            .validate()
+            .is_ok());
+
+        let mut entry = create();
+        entry.header.checksum ^= 1;
+        assert!(matches!(entry.validate(), Err(VhdxError::Crc32Error(_, _))));
+
+        let mut entry = create();
+        entry.descriptors[0].set_sequence_number(6);
+        entry.header.checksum = entry.crc32();
+        assert!(matches!(
+            entry.validate(),
+            Err(VhdxError::LogSequenceMismatch {
+                expected: 7,
+                found: 6
+            })
+        ));
+
+        let mut entry = create();
+        if let Descriptor::Data(d) = &mut entry.descriptors[1] {
+            d.data_sector.as_mut().unwrap().seq_low = 8;
+        }
+        entry.header.checksum = entry.crc32();
+        assert!(matches!(
+            entry.validate(),
+            Err(VhdxError::LogSequenceMismatch {
+                expected: 7,
+                found: 8
+            })
+        ));
+
+        // Misaligned, in the header section, past the last file offset or overflowing
+        for (file_offset, length) in [
+            (MB + 512, 4096),
+            (MB, 1000),
+            (0, 4096),
+            (316 * 1024, 8192),
+            (2 * MB - 4096, 8192),
+            (MB, u64::MAX - 4095),
+        ] {
+            let entry = LogEntry::create(
+                7,
+                0,
+                Uuid::nil(),
+                2 * MB,
+                2 * MB,
+                vec![Descriptor::zero(file_offset, length)],
+            );
+            assert!(matches!(
+                entry.validate(),
+                Err(VhdxError::InvalidLogDescriptor { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn entry_crc_with_full_descriptor_sector() {
+        let descriptors = (0..LogEntry::MAX_DESCRIPTORS)
+            .map(|i| Descriptor::zero(i as u64 * 4096, 4096))
+            .collect();
+        let entry = LogEntry::create(1, 0, Uuid::nil(), 0, 0, descriptors);
+        assert_eq!(4096, entry.entry_length());
+        assert_eq!(entry.header.checksum, entry.crc32());
+    }
+
+    #[test]
+    fn apply_to_with_extreme_ranges() {
+        let entry = entry_with(vec![
+            Descriptor::zero(MB, u64::MAX),
+            Descriptor::zero(u64::MAX - 4095, 4096),
+        ]);
+        let mut buffer = vec![0xFF; 8192];
+        entry.apply_to(MB + 4096, &mut buffer);
+        assert!(buffer.iter().all(|b| *b == 0));
+
+        // Nothing can be written past the last addressable offset
+        let mut buffer = vec![0xFF; 8192];
+        entry.apply_to(u64::MAX - 4096, &mut buffer);
+        assert!(buffer[..4096].iter().all(|b| *b == 0));
+        assert!(buffer[4096..].iter().all(|b| *b == 0xFF));
+    }
+
+    #[test]
+    fn entry_touches_described_ranges() {
+        let zero = entry_with(vec![Descriptor::Zero(ZeroDesc {
+            signature: Signature::Zero,
+            zero_length: 8192,
+            file_offset: 1024 * 1024,
+            seq_number: 1,
+        })]);
+        let data = entry_with(vec![Descriptor::Data(DataDesc {
+            signature: Signature::Desc,
+            trailing_bytes: vec![0; 4],
+            leading_bytes: vec![0; 8],
+            file_offset: 2 * 1024 * 1024,
+            seq_number: 1,
+            data_sector: None,
+        })]);
+
+        assert!(zero.touches(1024 * 1024 + 8191));
+        assert!(!zero.touches(1024 * 1024 + 8192));
+        assert!(!data.touches(1024 * 1024 + 4096));
+        assert!(data.touches(2 * 1024 * 1024 + 4095));
+        assert!(!data.touches(2 * 1024 * 1024 + 4096));
+    }
+
+    #[test]
+    fn truncated_log_entry() {
+        // The second entry of the fixture log carries data sectors after its descriptor sector
+        const ENTRY: usize = 1024 * 1024 + 4096;
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut reader = Cursor::new(&image[..ENTRY + 4096 + 100]);
+        reader.set_position(ENTRY as u64);
+        assert!(matches!(
+            LogEntry::deserialize(&mut reader),
+            Err(VhdxError::UnexpectedEof {
+                structure: "Log Entry",
+                offset,
+            }) if offset == ENTRY as u64
+        ));
+    }
 }
@@ -1,4 +1,7 @@
-use std::{collections::HashMap, io::SeekFrom};
+use std::{
+    collections::HashMap,
+    io::{SeekFrom, Write},
+};
 
 use super::Signature;
 use nom::{
@@ -18,7 +21,8 @@ use crate::{
         calc_total_bat_entries_differencing, calc_total_bat_entries_fixed_dynamic,
     },
     error::{VhdxError, VhdxParseError},
-    DeSerialise,
+    vhdx::Vhdx,
+    DeSerialise, Serialise,
 };
 
 use super::{
@@ -46,7 +50,12 @@ pub struct MetaData {
     pub sector_bitmaps_blocks_count: u64,
     pub total_bat_entries_fixed_dynamic: u64,
     pub total_bat_entries_differencing: u64,
+    pub parent_locator: Option<ParentLocator>,
     pub(crate) entries: HashMap<Uuid, Entry>,
+
+    // Bytes of the table and the items area that are not described by any parsed field, like
+    // stale table entries or gaps between items. Kept so the region can be written back exactly.
+    pub(crate) free_space: Vec<u8>,
 }
 
 impl MetaData {
@@ -59,6 +68,11 @@ impl MetaData {
     pub const PHYSICAL_SECTOR_SIZE: Uuid = uuid!("CDA348C7445D44719CC9E9885251C556");
     pub const PARENT_LOCATOR: Uuid = uuid!("A8D35F2DB30B454DABF7D3D84834AB0C");
 
+    const TABLE_SIZE: usize = 64 * 1024;
+
+    // The chunk ratio and BAT sizes are not stored in the file but derived from the block size,
+    // sector size and virtual disk size.
+    #[allow(clippy::too_many_arguments)]
     fn new(
         signature: Signature,
         entry_count: u16,
@@ -68,12 +82,40 @@ impl MetaData {
         virtual_disk_id: Uuid,
         logical_sector_size: SectorSize,
         physical_sector_size: SectorSize,
-        chunk_ratio: u64,
-        payload_blocks_count: u64,
-        sector_bitmaps_blocks_count: u64,
-        total_bat_entries_fixed_dynamic: u64,
-        total_bat_entries_differencing: u64,
+        parent_locator: Option<ParentLocator>,
     ) -> Self {
+        let chunk_ratio = calc_chunk_ratio(logical_sector_size, file_parameters.block_size);
+
+        let payload_blocks_count =
+            calc_payload_blocks_count(virtual_disk_size, file_parameters.block_size);
+
+        let sector_bitmaps_blocks_count =
+            calc_sector_bitmap_blocks_count(payload_blocks_count as usize, chunk_ratio as usize);
+
+        let total_bat_entries_fixed_dynamic =
+            calc_total_bat_entries_fixed_dynamic(payload_blocks_count, chunk_ratio);
+        let total_bat_entries_differencing =
+            calc_total_bat_entries_differencing(sector_bitmaps_blocks_count, chunk_ratio);
+
+        let mut entries = entries;
+        for entry in entries.values_mut() {
+            let value = match entry.item_id {
+                MetaData::FILE_PARAMETERS => MetaValue::FileParameters(file_parameters.clone()),
+                MetaData::VIRTUAL_DISK_SIZE => MetaValue::VirtualDiskSize(virtual_disk_size),
+                MetaData::VIRTUAL_DISK_ID => MetaValue::VirtualDiskId(virtual_disk_id),
+                MetaData::LOGICAL_SECTOR_SIZE => MetaValue::LogicalSectorSize(logical_sector_size),
+                MetaData::PHYSICAL_SECTOR_SIZE => {
+                    MetaValue::PhysicalSectorSize(physical_sector_size)
+                }
+                MetaData::PARENT_LOCATOR => match &parent_locator {
+                    Some(locator) => MetaValue::ParentLocator(locator.clone()),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            entry.value = value;
+        }
+
         Self {
             signature,
             entry_count,
@@ -88,8 +130,102 @@ impl MetaData {
             sector_bitmaps_blocks_count,
             total_bat_entries_fixed_dynamic,
             total_bat_entries_differencing,
+            parent_locator,
+            free_space: Vec::new(),
         }
     }
+
+    // Lays out the items of a new metadata region the same way Hyper-V does, the table takes up
+    // the first 64 KB and the item data follows directly after it.
+    pub(crate) fn create(
+        file_parameters: FileParameters,
+        virtual_disk_size: usize,
+        virtual_disk_id: Uuid,
+        logical_sector_size: SectorSize,
+        physical_sector_size: SectorSize,
+        parent_locator: Option<ParentLocator>,
+    ) -> Self {
+        let mut items = vec![
+            (MetaData::FILE_PARAMETERS, 8, false, true),
+            (MetaData::VIRTUAL_DISK_SIZE, 8, true, true),
+            (MetaData::LOGICAL_SECTOR_SIZE, 4, true, true),
+            (MetaData::PHYSICAL_SECTOR_SIZE, 4, true, true),
+            (MetaData::VIRTUAL_DISK_ID, 16, true, true),
+        ];
+        if let Some(locator) = &parent_locator {
+            items.push((
+                MetaData::PARENT_LOCATOR,
+                locator.to_bytes().len(),
+                false,
+                true,
+            ));
+        }
+
+        let mut offset = MetaData::TABLE_SIZE;
+        let entries: HashMap<Uuid, Entry> = items
+            .into_iter()
+            .map(|(id, length, is_virtual_disk, is_required)| {
+                let entry = Entry::new(id, offset, length, false, is_virtual_disk, is_required);
+                offset += length;
+                (id, entry)
+            })
+            .collect();
+
+        MetaData::new(
+            Signature::MetaData,
+            entries.len() as u16,
+            entries,
+            file_parameters,
+            virtual_disk_size,
+            virtual_disk_id,
+            logical_sector_size,
+            physical_sector_size,
+            parent_locator,
+        )
+    }
+
+    pub fn get_entry(&self, id: Uuid) -> Option<&Entry> {
+        self.entries.get(&id)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.values()
+    }
+
+    pub fn file_parameters(&self) -> &FileParameters {
+        &self.file_parameters
+    }
+
+    pub fn virtual_disk_size(&self) -> usize {
+        self.virtual_disk_size
+    }
+
+    pub fn virtual_disk_id(&self) -> Uuid {
+        self.virtual_disk_id
+    }
+
+    pub fn logical_sector_size(&self) -> SectorSize {
+        self.logical_sector_size
+    }
+
+    pub fn physical_sector_size(&self) -> SectorSize {
+        self.physical_sector_size
+    }
+
+    // Number of entries in the BAT, differencing disks also carry the sector bitmap entry after
+    // the last chunk
+    pub fn bat_entry_count(&self) -> u64 {
+        if self.file_parameters.has_parent {
+            self.total_bat_entries_differencing
+        } else {
+            self.total_bat_entries_fixed_dynamic
+        }
+    }
+
+    // Only present for differencing disks
+    pub fn parent_locator(&self) -> Option<&ParentLocator> {
+        self.parent_locator.as_ref()
+    }
 }
 
 impl<T> DeSerialise<T> for MetaData {
@@ -99,22 +235,43 @@ impl<T> DeSerialise<T> for MetaData {
     where
         T: std::io::Read + std::io::Seek,
     {
+        let offset = reader.stream_position()?;
+        MetaData::read_from(reader).map_err(|e| e.at_structure("Metadata", offset))
+    }
+}
+
+impl MetaData {
+    fn read_from<T: std::io::Read + std::io::Seek>(reader: &mut T) -> Result<MetaData, VhdxError> {
         let start_pos = reader.stream_position()?;
 
         let mut buffer = [0; 32];
         reader.read_exact(&mut buffer)?;
-        let (_, (signature, entry_count)) = parse_header(&buffer).unwrap();
+        let (_, (signature, entry_count)) = parse_header(&buffer)?;
 
+        // The 64KB table holds at most 2047 entries after its header, a larger count would read
+        // into the metadata items
+        let max_entries = (MetaData::TABLE_SIZE - 32) / 32;
+        if entry_count as usize > max_entries {
+            return Err(VhdxError::RTEntryCountError(entry_count.into()));
+        }
         let mut entries = HashMap::new();
-        for _ in 0..5 {
+        for _ in 0..entry_count {
             let mut buffer = [0; 32];
             reader.read_exact(&mut buffer)?;
 
-            let (_, (signature, offset, length, a, b, c)) = parse_entry(&buffer).unwrap();
+            let (_, (signature, offset, length, a, b, c)) = parse_entry(&buffer)?;
+            // An all zero item id marks free space in the table
+            if signature.is_nil() {
+                continue;
+            }
+            // Items are stored after the table header, only empty items may have a zero offset
+            if length > 0 && offset < 32 {
+                return Err(VhdxError::MetadataEntryOffsetInvalid(offset));
+            }
 
             let start_next = reader.stream_position()?;
 
-            let entry = Entry::new(signature, offset, length, a, b, c);
+            let mut entry = Entry::new(signature, offset, length, a, b, c);
             match signature {
                 MetaData::FILE_PARAMETERS => {
                     entries.insert(MetaData::FILE_PARAMETERS, entry);
@@ -131,55 +288,130 @@ impl<T> DeSerialise<T> for MetaData {
                 MetaData::PHYSICAL_SECTOR_SIZE => {
                     entries.insert(MetaData::PHYSICAL_SECTOR_SIZE, entry);
                 }
-                _ => panic!("Could not identify signature for read metadata entry"),
+                MetaData::PARENT_LOCATOR => {
+                    entries.insert(MetaData::PARENT_LOCATOR, entry);
+                }
+                // Unknown items may be ignored unless they are marked as required
+                _ if !entry.is_required && entry.length as u64 <= Vhdx::MB => {
+                    reader.seek(SeekFrom::Start(
+                        start_pos.saturating_add(entry.offset as u64),
+                    ))?;
+                    let mut buffer = vec![0; entry.length];
+                    reader.read_exact(&mut buffer)?;
+                    entry.value = MetaValue::Unknown(buffer);
+                    entries.insert(signature, entry);
+                }
+                // Optional items over the 1MB item limit are skipped instead of buffered
+                _ if !entry.is_required => {}
+                _ => return Err(VhdxError::UnknownRequiredMetadataItem(signature)),
             }
             reader.seek(SeekFrom::Start(start_next))?;
         }
 
-        let entry = entries[&MetaData::FILE_PARAMETERS];
-        reader.seek(SeekFrom::Start(start_pos + entry.offset as u64))?;
+        let entry = get_entry(&entries, MetaData::FILE_PARAMETERS, "File Parameters")?;
+        reader.seek(SeekFrom::Start(
+            start_pos.saturating_add(entry.offset as u64),
+        ))?;
         let mut buffer = [0; 8];
         reader.read_exact(&mut buffer)?;
-        let (_, file_parameters) = parse_file_params(&buffer).unwrap();
+        let (_, file_parameters) = parse_file_params(&buffer)?;
 
-        let entry = entries[&MetaData::VIRTUAL_DISK_SIZE];
-        reader.seek(SeekFrom::Start(start_pos + entry.offset as u64))?;
+        let entry = get_entry(&entries, MetaData::VIRTUAL_DISK_SIZE, "Virtual Disk Size")?;
+        reader.seek(SeekFrom::Start(
+            start_pos.saturating_add(entry.offset as u64),
+        ))?;
         let mut buffer = [0; 8];
         reader.read_exact(&mut buffer)?;
-        let (_, virtual_disk_size) = t_v_disk_size(&buffer).unwrap();
+        let (_, virtual_disk_size) = t_v_disk_size(&buffer)?;
 
-        let entry = entries[&MetaData::VIRTUAL_DISK_ID];
-        reader.seek(SeekFrom::Start(start_pos + entry.offset as u64))?;
+        let entry = get_entry(&entries, MetaData::VIRTUAL_DISK_ID, "Virtual Disk Id")?;
+        reader.seek(SeekFrom::Start(
+            start_pos.saturating_add(entry.offset as u64),
+        ))?;
         let mut buffer = [0; 16];
         reader.read_exact(&mut buffer)?;
-        let (_, virtual_disk_id) = t_guid(&buffer).unwrap();
+        let (_, virtual_disk_id) = t_guid(&buffer)?;
 
-        let entry = entries[&MetaData::LOGICAL_SECTOR_SIZE];
-        reader.seek(SeekFrom::Start(start_pos + entry.offset as u64))?;
+        let entry = get_entry(
+            &entries,
+            MetaData::LOGICAL_SECTOR_SIZE,
+            "Logical Sector Size",
+        )?;
+        reader.seek(SeekFrom::Start(
+            start_pos.saturating_add(entry.offset as u64),
+        ))?;
         let mut buffer = [0; 4];
         reader.read_exact(&mut buffer)?;
-        let (_, logical_sector_size) = t_sector_size(&buffer).unwrap();
+        let logical_sector_size = parse_sector_size(&buffer)?;
 
-        let entry = entries[&MetaData::PHYSICAL_SECTOR_SIZE];
-        reader.seek(SeekFrom::Start(start_pos + entry.offset as u64))?;
+        let entry = get_entry(
+            &entries,
+            MetaData::PHYSICAL_SECTOR_SIZE,
+            "Physical Sector Size",
+        )?;
+        reader.seek(SeekFrom::Start(
+            start_pos.saturating_add(entry.offset as u64),
+        ))?;
         let mut buffer = [0; 4];
         reader.read_exact(&mut buffer)?;
-        let (_, physical_sector_size) = t_sector_size(&buffer).unwrap();
+        let physical_sector_size = parse_sector_size(&buffer)?;
 
-        let chunk_ratio = calc_chunk_ratio(logical_sector_size, file_parameters.block_size);
+        let parent_locator = match entries.get(&MetaData::PARENT_LOCATOR) {
+            Some(entry) if entry.length as u64 <= Vhdx::MB => {
+                reader.seek(SeekFrom::Start(
+                    start_pos.saturating_add(entry.offset as u64),
+                ))?;
+                let mut buffer = vec![0; entry.length];
+                reader.read_exact(&mut buffer)?;
+                Some(ParentLocator::from_bytes(&buffer)?)
+            }
+            Some(entry) => {
+                return Err(VhdxError::ParseError(format!(
+                    "Parent locator larger than 1MB: {}",
+                    entry.length
+                )))
+            }
+            None => None,
+        };
 
-        let payload_blocks_count =
-            calc_payload_blocks_count(virtual_disk_size, file_parameters.block_size);
+        // Block size MUST be a power of two between 1MB and 256MB, anything else would make the
+        // BAT calculations meaningless.
+        let block_size = file_parameters.block_size as u64;
+        if !(Vhdx::MB..=256 * Vhdx::MB).contains(&block_size) || !block_size.is_power_of_two() {
+            return Err(VhdxError::BlockSizeError(block_size));
+        }
 
-        let sector_bitmaps_blocks_count =
-            calc_sector_bitmap_blocks_count(payload_blocks_count as usize, chunk_ratio as usize);
+        // The virtual disk size MUST be a multiple of the logical sector size
+        let sector_size = logical_sector_size.bytes();
+        if !(virtual_disk_size as u64).is_multiple_of(sector_size) {
+            return Err(VhdxError::VirtualSizeNotSectorAligned(
+                virtual_disk_size as u64,
+                sector_size,
+            ));
+        }
 
-        let total_bat_entries_fixed_dynamic =
-            calc_total_bat_entries_fixed_dynamic(payload_blocks_count, chunk_ratio);
-        let total_bat_entries_differencing =
-            calc_total_bat_entries_differencing(sector_bitmaps_blocks_count, chunk_ratio);
+        // Everything the serializer rewrites is cleared, the rest is free space. Like the parent
+        // locator it is only kept up to 1MB, the size Hyper-V gives the whole region.
+        let span = entries
+            .values()
+            .map(|entry| entry.offset + entry.length)
+            .max()
+            .unwrap_or(0)
+            .max(MetaData::TABLE_SIZE);
+        let mut free_space = Vec::new();
+        if span as u64 <= Vhdx::MB {
+            free_space.resize(span, 0);
+            reader.seek(SeekFrom::Start(start_pos))?;
+            reader.read_exact(&mut free_space)?;
+            free_space[..8].fill(0);
+            free_space[10..12].fill(0);
+            free_space[32..32 + entries.len() * 32].fill(0);
+            for entry in entries.values() {
+                free_space[entry.offset..entry.offset + entry.length].fill(0);
+            }
+        }
 
-        Ok(MetaData::new(
+        let mut meta_data = MetaData::new(
             signature,
             entry_count,
             entries,
@@ -188,21 +420,81 @@ impl<T> DeSerialise<T> for MetaData {
             virtual_disk_id,
             logical_sector_size,
             physical_sector_size,
-            chunk_ratio,
-            payload_blocks_count,
-            sector_bitmaps_blocks_count,
-            total_bat_entries_fixed_dynamic,
-            total_bat_entries_differencing,
-        ))
+            parent_locator,
+        );
+        meta_data.free_space = free_space;
+        Ok(meta_data)
     }
 }
 
-fn t_sector_size(buffer: &[u8]) -> IResult<&[u8], SectorSize> {
-    map(le_u32, |v: u32| match v.try_into() {
-        Ok(SectorSize::Sector512) => SectorSize::Sector512,
-        Ok(SectorSize::Sector4096) => SectorSize::Sector4096,
-        Err(_) => todo!(),
-    })(buffer)
+impl Serialise for MetaData {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), VhdxError> {
+        let mut entries: Vec<&Entry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| entry.offset);
+
+        let length = entries
+            .iter()
+            .map(|entry| entry.offset + entry.length)
+            .max()
+            .unwrap_or(0)
+            .max(MetaData::TABLE_SIZE)
+            .max(self.free_space.len());
+        let mut buffer = vec![0; length];
+        buffer[..self.free_space.len()].copy_from_slice(&self.free_space);
+
+        buffer[..8].copy_from_slice(MetaData::SIGN);
+        buffer[10..12].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+        for (i, entry) in entries.iter().enumerate() {
+            let start = 32 + i * 32;
+            buffer[start..start + 32].copy_from_slice(&entry.to_bytes());
+
+            let value = match entry.item_id {
+                MetaData::FILE_PARAMETERS => self.file_parameters.to_bytes().to_vec(),
+                MetaData::VIRTUAL_DISK_SIZE => {
+                    (self.virtual_disk_size as u64).to_le_bytes().to_vec()
+                }
+                MetaData::VIRTUAL_DISK_ID => self.virtual_disk_id.to_bytes_le().to_vec(),
+                MetaData::LOGICAL_SECTOR_SIZE => {
+                    (self.logical_sector_size as u32).to_le_bytes().to_vec()
+                }
+                MetaData::PHYSICAL_SECTOR_SIZE => {
+                    (self.physical_sector_size as u32).to_le_bytes().to_vec()
+                }
+                MetaData::PARENT_LOCATOR => self
+                    .parent_locator
+                    .as_ref()
+                    .map(ParentLocator::to_bytes)
+                    .unwrap_or_default(),
+                _ => match &entry.value {
+                    MetaValue::Unknown(bytes) => bytes.clone(),
+                    _ => Vec::new(),
+                },
+            };
+            let value_length = value.len().min(entry.length);
+            buffer[entry.offset..entry.offset + value_length]
+                .copy_from_slice(&value[..value_length]);
+        }
+
+        writer.write_all(&buffer)?;
+        Ok(())
+    }
+}
+
+fn get_entry(
+    entries: &HashMap<Uuid, Entry>,
+    id: Uuid,
+    name: &'static str,
+) -> Result<Entry, VhdxError> {
+    entries
+        .get(&id)
+        .cloned()
+        .ok_or(VhdxError::MissingMetadataEntry(name))
+}
+
+// Not a nom parser so the unsupported size can be reported instead of a generic parse failure
+fn parse_sector_size(buffer: &[u8]) -> Result<SectorSize, VhdxError> {
+    let (_, value) = le_u32::<_, VhdxParseError<&[u8]>>(buffer)?;
+    SectorSize::try_from(value).map_err(|_| VhdxError::InvalidSectorSize(value))
 }
 
 fn parse_header(reader: &[u8]) -> IResult<&[u8], (Signature, u16), VhdxParseError<&[u8]>> {
@@ -212,11 +504,11 @@ fn parse_header(reader: &[u8]) -> IResult<&[u8], (Signature, u16), VhdxParseErro
     )(reader)
 }
 
-fn t_v_disk_size(buffer: &[u8]) -> IResult<&[u8], usize> {
+fn t_v_disk_size(buffer: &[u8]) -> IResult<&[u8], usize, VhdxParseError<&[u8]>> {
     map(le_u64, |v| v as usize)(buffer)
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct Entry {
     pub item_id: Uuid,
     pub offset: usize,
@@ -224,6 +516,19 @@ pub struct Entry {
     pub is_user: bool,
     pub is_virtual_disk: bool,
     pub is_required: bool,
+    // Parsed value of the item, items this implementation doesn't know keep their raw bytes
+    pub value: MetaValue,
+}
+
+#[derive(Debug, Clone)]
+pub enum MetaValue {
+    FileParameters(FileParameters),
+    VirtualDiskSize(usize),
+    VirtualDiskId(Uuid),
+    LogicalSectorSize(SectorSize),
+    PhysicalSectorSize(SectorSize),
+    ParentLocator(ParentLocator),
+    Unknown(Vec<u8>),
 }
 
 impl Entry {
@@ -242,13 +547,24 @@ impl Entry {
             is_user,
             is_virtual_disk,
             is_required,
+            value: MetaValue::Unknown(Vec::new()),
         }
     }
+
+    fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        bytes[..16].copy_from_slice(&self.item_id.to_bytes_le());
+        bytes[16..20].copy_from_slice(&(self.offset as u32).to_le_bytes());
+        bytes[20..24].copy_from_slice(&(self.length as u32).to_le_bytes());
+        bytes[24] =
+            self.is_user as u8 | (self.is_virtual_disk as u8) << 1 | (self.is_required as u8) << 2;
+        bytes
+    }
 }
 
-fn parse_entry(
-    buffer: &[u8],
-) -> IResult<&[u8], (Uuid, usize, usize, bool, bool, bool), VhdxParseError<&[u8]>> {
+type EntryFields = (Uuid, usize, usize, bool, bool, bool);
+
+fn parse_entry(buffer: &[u8]) -> IResult<&[u8], EntryFields, VhdxParseError<&[u8]>> {
     map(
         tuple((t_guid, le_u32, le_u32, bits(t_3_flags_u32), take(7usize))),
         |(guid, offset, length, (is_user, is_virtual_disk, is_required), _)| {
@@ -281,27 +597,570 @@ pub enum SectorSize {
     Sector4096 = 4096,
 }
 
+impl SectorSize {
+    pub fn bytes(&self) -> u64 {
+        *self as u64
+    }
+}
+
 impl TryFrom<u32> for SectorSize {
     type Error = ();
 
     fn try_from(v: u32) -> Result<Self, Self::Error> {
         match v {
             x if x == SectorSize::Sector512 as u32 => Ok(SectorSize::Sector512),
-            x if x == SectorSize::Sector4096 as u32 => Ok(SectorSize::Sector512),
+            x if x == SectorSize::Sector4096 as u32 => Ok(SectorSize::Sector4096),
             _ => Err(()),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum LocatorTypeEntry {
     Guid(Uuid),
     Path(String),
 }
 
-#[derive(Debug)]
+// The parent locator of a differencing disk is a list of UTF-16 key value pairs describing where
+// the parent can be found, every offset is relative to the start of the parent locator item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParentLocator {
+    // LocatorType (16 bytes): MUST be B04AEFB7-D19E-4A81-B789-25B8E9445913 for VHDX parents.
+    pub locator_type: Uuid,
+    pub entries: Vec<(String, LocatorTypeEntry)>,
+}
+
+impl ParentLocator {
+    pub const VHDX_PARENT_LOCATOR: Uuid = uuid!("B04AEFB7D19E4A81B78925B8E9445913");
+
+    pub const PARENT_LINKAGE: &'static str = "parent_linkage";
+    pub const RELATIVE_PATH: &'static str = "relative_path";
+    pub const ABSOLUTE_WIN32_PATH: &'static str = "absolute_win32_path";
+
+    const HEADER_SIZE: usize = 20;
+    const ENTRY_SIZE: usize = 12;
+
+    pub fn new(entries: Vec<(String, LocatorTypeEntry)>) -> Self {
+        Self {
+            locator_type: ParentLocator::VHDX_PARENT_LOCATOR,
+            entries,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&LocatorTypeEntry> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value)
+    }
+
+    pub fn parent_linkage(&self) -> Option<Uuid> {
+        match self.get(ParentLocator::PARENT_LINKAGE) {
+            Some(LocatorTypeEntry::Guid(guid)) => Some(*guid),
+            _ => None,
+        }
+    }
+
+    pub fn relative_path(&self) -> Option<&str> {
+        self.path(ParentLocator::RELATIVE_PATH)
+    }
+
+    pub fn absolute_win32_path(&self) -> Option<&str> {
+        self.path(ParentLocator::ABSOLUTE_WIN32_PATH)
+    }
+
+    fn path(&self, key: &str) -> Option<&str> {
+        match self.get(key) {
+            Some(LocatorTypeEntry::Path(path)) => Some(path),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn from_bytes(buffer: &[u8]) -> Result<Self, VhdxError> {
+        let (_, (locator_type, count)) = parse_locator_header(buffer)?;
+
+        let mut entries = Vec::with_capacity(count as usize);
+        for i in 0..count as usize {
+            let start = ParentLocator::HEADER_SIZE + i * ParentLocator::ENTRY_SIZE;
+            let (_, (key_offset, value_offset, key_length, value_length)) =
+                parse_locator_entry(buffer.get(start..).unwrap_or_default())?;
+            let key = utf16_string(buffer, key_offset as usize, key_length as usize)?;
+            let value = utf16_string(buffer, value_offset as usize, value_length as usize)?;
+
+            let value = match Uuid::parse_str(&value) {
+                Ok(guid) if key.starts_with(ParentLocator::PARENT_LINKAGE) => {
+                    LocatorTypeEntry::Guid(guid)
+                }
+                _ => LocatorTypeEntry::Path(value),
+            };
+            entries.push((key, value));
+        }
+
+        Ok(Self {
+            locator_type,
+            entries,
+        })
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let strings: Vec<(Vec<u8>, Vec<u8>)> = self
+            .entries
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    LocatorTypeEntry::Guid(guid) => guid.braced().to_string().to_uppercase(),
+                    LocatorTypeEntry::Path(path) => path.clone(),
+                };
+                (utf16_bytes(key), utf16_bytes(&value))
+            })
+            .collect();
+
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&self.locator_type.to_bytes_le());
+        buffer.extend_from_slice(&[0; 2]);
+        buffer.extend_from_slice(&(strings.len() as u16).to_le_bytes());
+
+        let mut offset = ParentLocator::HEADER_SIZE + strings.len() * ParentLocator::ENTRY_SIZE;
+        for (key, value) in &strings {
+            buffer.extend_from_slice(&(offset as u32).to_le_bytes());
+            buffer.extend_from_slice(&((offset + key.len()) as u32).to_le_bytes());
+            buffer.extend_from_slice(&(key.len() as u16).to_le_bytes());
+            buffer.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            offset += key.len() + value.len();
+        }
+        for (key, value) in strings {
+            buffer.extend_from_slice(&key);
+            buffer.extend_from_slice(&value);
+        }
+        buffer
+    }
+}
+
+fn parse_locator_header(buffer: &[u8]) -> IResult<&[u8], (Uuid, u16), VhdxParseError<&[u8]>> {
+    map(
+        tuple((t_guid, le_u16, le_u16)),
+        |(locator_type, _, count)| (locator_type, count),
+    )(buffer)
+}
+
+// Key offset, value offset, key length and value length
+type LocatorEntryFields = (u32, u32, u16, u16);
+
+fn parse_locator_entry(buffer: &[u8]) -> IResult<&[u8], LocatorEntryFields, VhdxParseError<&[u8]>> {
+    tuple((le_u32, le_u32, le_u16, le_u16))(buffer)
+}
+
+fn utf16_string(buffer: &[u8], offset: usize, length: usize) -> Result<String, VhdxError> {
+    let bytes = offset
+        .checked_add(length)
+        .and_then(|end| buffer.get(offset..end))
+        .ok_or_else(|| VhdxError::ParseError("Parent locator entry out of bounds".to_string()))?;
+    let chars: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .collect();
+    String::from_utf16(&chars).map_err(|e| VhdxError::ParseError(e.to_string()))
+}
+
+fn utf16_bytes(value: &str) -> Vec<u8> {
+    value.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+#[derive(Debug, Clone)]
 pub struct FileParameters {
     pub block_size: usize,
     pub leave_block_allocated: bool,
     pub has_parent: bool,
 }
+
+// Kind of virtual disk as classified by the file parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskType {
+    Fixed,
+    Dynamic,
+    Differencing,
+}
+
+impl std::fmt::Display for DiskType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            DiskType::Fixed => "Fixed",
+            DiskType::Dynamic => "Dynamic",
+            DiskType::Differencing => "Differencing",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FileParameters {
+    // A disk with a parent is differencing whether or not its blocks are preallocated, otherwise
+    // preallocated blocks make it a fixed disk.
+    pub fn disk_type(&self) -> DiskType {
+        match (self.has_parent, self.leave_block_allocated) {
+            (true, _) => DiskType::Differencing,
+            (false, true) => DiskType::Fixed,
+            (false, false) => DiskType::Dynamic,
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; 8] {
+        let mut bytes = [0; 8];
+        bytes[..4].copy_from_slice(&(self.block_size as u32).to_le_bytes());
+        bytes[4] = self.leave_block_allocated as u8 | (self.has_parent as u8) << 1;
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Seek;
+
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn file_parameter_flags_from_low_bits() {
+        let params = |flags: u32| {
+            let mut bytes = (2 * 1024 * 1024_u32).to_le_bytes().to_vec();
+            bytes.extend_from_slice(&flags.to_le_bytes());
+            parse_file_params(&bytes).unwrap().1
+        };
+
+        // Bit 0 is LeaveBlockAllocated, bit 1 is HasParent
+        let leave_allocated = params(0b01);
+        assert!(leave_allocated.leave_block_allocated);
+        assert!(!leave_allocated.has_parent);
+
+        let has_parent = params(0b10);
+        assert!(!has_parent.leave_block_allocated);
+        assert!(has_parent.has_parent);
+
+        // Bits 2 and 3 are reserved
+        let reserved = params(0b1100);
+        assert!(!reserved.leave_block_allocated);
+        assert!(!reserved.has_parent);
+    }
+
+    #[test]
+    fn round_trip_keeps_free_space() {
+        const META_DATA: usize = 2 * 1024 * 1024;
+        let image = std::fs::read("test.vhdx").unwrap();
+        let meta_data = read_fixture_meta_data();
+        // The stale entry behind the last counted one only survives as free space
+        assert!(meta_data.free_space.iter().any(|b| *b != 0));
+
+        let mut buffer = Vec::new();
+        meta_data.serialize(&mut buffer).unwrap();
+        assert_eq!(&image[META_DATA..META_DATA + buffer.len()], &buffer[..]);
+    }
+
+    #[test]
+    fn bat_entry_count_follows_has_parent() {
+        let mut meta_data = read_fixture_meta_data();
+        assert_eq!(2, meta_data.bat_entry_count());
+        assert_eq!(
+            meta_data.total_bat_entries_fixed_dynamic,
+            meta_data.bat_entry_count()
+        );
+
+        meta_data.file_parameters.has_parent = true;
+        assert_eq!(
+            meta_data.total_bat_entries_differencing,
+            meta_data.bat_entry_count()
+        );
+    }
+
+    #[test]
+    fn parent_locator_round_trip() {
+        let linkage = uuid!("0F0E0D0C0B0A09080706050403020100");
+        let locator = ParentLocator::new(vec![
+            (
+                ParentLocator::PARENT_LINKAGE.to_string(),
+                LocatorTypeEntry::Guid(linkage),
+            ),
+            (
+                ParentLocator::RELATIVE_PATH.to_string(),
+                LocatorTypeEntry::Path(".\\parent.vhdx".to_string()),
+            ),
+            (
+                ParentLocator::ABSOLUTE_WIN32_PATH.to_string(),
+                LocatorTypeEntry::Path("C:\\disks\\parent.vhdx".to_string()),
+            ),
+        ]);
+
+        let parsed = ParentLocator::from_bytes(&locator.to_bytes()).unwrap();
+        assert_eq!(ParentLocator::VHDX_PARENT_LOCATOR, parsed.locator_type);
+        assert_eq!(Some(linkage), parsed.parent_linkage());
+        assert_eq!(Some(".\\parent.vhdx"), parsed.relative_path());
+        assert_eq!(Some("C:\\disks\\parent.vhdx"), parsed.absolute_win32_path());
+        assert_eq!(locator, parsed);
+
+        // The fixture is not a differencing disk
+        assert!(read_fixture_meta_data().parent_locator().is_none());
+    }
+
+    #[test]
+    fn sector_size_from_u32() {
+        for bytes in [512, 4096] {
+            let sector_size = SectorSize::try_from(bytes).unwrap();
+            assert_eq!(bytes as u64, sector_size.bytes());
+        }
+        assert!(SectorSize::try_from(1024).is_err());
+
+        // The chunk ratio of a 4K disk is eight times the one of a 512 byte disk
+        let block_size = 2 * 1024 * 1024;
+        assert_eq!(2048, calc_chunk_ratio(SectorSize::Sector512, block_size));
+        assert_eq!(16384, calc_chunk_ratio(SectorSize::Sector4096, block_size));
+    }
+
+    #[test]
+    fn disk_type_from_file_parameters() {
+        let disk_type = |leave_block_allocated, has_parent| {
+            FileParameters {
+                block_size: 1024 * 1024,
+                leave_block_allocated,
+                has_parent,
+            }
+            .disk_type()
+        };
+        assert_eq!(DiskType::Dynamic, disk_type(false, false));
+        assert_eq!(DiskType::Fixed, disk_type(true, false));
+        assert_eq!(DiskType::Differencing, disk_type(false, true));
+        assert_eq!(DiskType::Differencing, disk_type(true, true));
+        assert_eq!("Differencing", DiskType::Differencing.to_string());
+    }
+
+    fn read_fixture_meta_data() -> MetaData {
+        let mut reader = File::open("test.vhdx").unwrap();
+        reader.seek(SeekFrom::Start(2 * 1024 * 1024)).unwrap();
+        MetaData::deserialize(&mut reader).unwrap()
+    }
+
+    #[test]
+    fn get_entry_by_guid() {
+        let meta_data = read_fixture_meta_data();
+
+        let entry = meta_data.get_entry(MetaData::VIRTUAL_DISK_ID).unwrap();
+        assert_eq!(MetaData::VIRTUAL_DISK_ID, entry.item_id);
+        assert_eq!(65560, entry.offset);
+        assert_eq!(16, entry.length);
+
+        assert!(meta_data.get_entry(MetaData::PARENT_LOCATOR).is_none());
+        assert_eq!(5, meta_data.entries().count());
+    }
+
+    #[test]
+    fn unrecognized_sector_size_is_error() {
+        assert!(matches!(
+            parse_sector_size(&1024_u32.to_le_bytes()),
+            Err(VhdxError::InvalidSectorSize(1024))
+        ));
+        assert!(matches!(
+            parse_sector_size(&0_u32.to_le_bytes()),
+            Err(VhdxError::InvalidSectorSize(0))
+        ));
+        assert!(matches!(
+            parse_sector_size(&512_u32.to_le_bytes()),
+            Ok(SectorSize::Sector512)
+        ));
+    }
+
+    #[test]
+    fn invalid_sector_size_in_metadata() {
+        let mut reader = File::open("test.vhdx").unwrap();
+        reader.seek(SeekFrom::Start(2 * 1024 * 1024)).unwrap();
+        let meta_data = MetaData::deserialize(&mut reader).unwrap();
+        let offset = meta_data
+            .get_entry(MetaData::LOGICAL_SECTOR_SIZE)
+            .unwrap()
+            .offset;
+
+        const META_DATA: usize = 2 * 1024 * 1024;
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        let item = META_DATA + offset;
+        image[item..item + 4].copy_from_slice(&1000_u32.to_le_bytes());
+
+        let mut reader = std::io::Cursor::new(image);
+        reader.seek(SeekFrom::Start(META_DATA as u64)).unwrap();
+        assert!(matches!(
+            MetaData::deserialize(&mut reader),
+            Err(VhdxError::InvalidSectorSize(1000))
+        ));
+    }
+
+    #[test]
+    fn virtual_size_not_sector_aligned() {
+        const META_DATA: usize = 2 * 1024 * 1024;
+        const VIRTUAL_DISK_SIZE: usize = META_DATA + 0x10008;
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        let size = 4 * 1024 * 1024 + 100_u64;
+        image[VIRTUAL_DISK_SIZE..VIRTUAL_DISK_SIZE + 8].copy_from_slice(&size.to_le_bytes());
+
+        let mut reader = std::io::Cursor::new(image);
+        reader.seek(SeekFrom::Start(META_DATA as u64)).unwrap();
+        assert!(matches!(
+            MetaData::deserialize(&mut reader),
+            Err(VhdxError::VirtualSizeNotSectorAligned(4194404, 512))
+        ));
+    }
+
+    #[test]
+    fn entries_carry_typed_values() {
+        let meta_data = read_fixture_meta_data();
+
+        let entry = meta_data.get_entry(MetaData::VIRTUAL_DISK_SIZE).unwrap();
+        assert!(matches!(entry.value, MetaValue::VirtualDiskSize(4194304)));
+
+        let entry = meta_data.get_entry(MetaData::FILE_PARAMETERS).unwrap();
+        match &entry.value {
+            MetaValue::FileParameters(file_parameters) => {
+                assert_eq!(2 * 1024 * 1024, file_parameters.block_size);
+                assert!(!file_parameters.has_parent);
+            }
+            other => panic!("expected file parameters, got: {:?}", other),
+        }
+
+        assert!(meta_data
+            .entries()
+            .all(|entry| !matches!(entry.value, MetaValue::Unknown(_))));
+    }
+
+    #[test]
+    fn optional_unknown_entry_keeps_raw_bytes() {
+        const META_DATA: usize = 2 * 1024 * 1024;
+        let unknown = uuid!("0F0E0D0C0B0A09080706050403020100");
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        image[META_DATA + 10..META_DATA + 12].copy_from_slice(&6_u16.to_le_bytes());
+        let entry = META_DATA + 32 + 5 * 32;
+        image[entry..entry + 16].copy_from_slice(&unknown.to_bytes_le());
+        image[entry + 16..entry + 20].copy_from_slice(&0x10030_u32.to_le_bytes());
+        image[entry + 20..entry + 24].copy_from_slice(&4_u32.to_le_bytes());
+        // Not required, not virtual disk, not user
+        image[entry + 24..entry + 32].fill(0);
+        image[META_DATA + 0x10030..META_DATA + 0x10034].copy_from_slice(b"abcd");
+
+        let mut reader = std::io::Cursor::new(image);
+        reader.seek(SeekFrom::Start(META_DATA as u64)).unwrap();
+        let meta_data = MetaData::deserialize(&mut reader).unwrap();
+        match &meta_data.get_entry(unknown).unwrap().value {
+            MetaValue::Unknown(bytes) => assert_eq!(b"abcd", &bytes[..]),
+            other => panic!("expected raw bytes, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn oversized_optional_unknown_entry_skipped() {
+        const META_DATA: usize = 2 * 1024 * 1024;
+        let unknown = uuid!("0F0E0D0C0B0A09080706050403020100");
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        image[META_DATA + 10..META_DATA + 12].copy_from_slice(&6_u16.to_le_bytes());
+        let entry = META_DATA + 32 + 5 * 32;
+        image[entry..entry + 16].copy_from_slice(&unknown.to_bytes_le());
+        image[entry + 16..entry + 20].copy_from_slice(&0x10000_u32.to_le_bytes());
+        image[entry + 20..entry + 24].copy_from_slice(&(2 * 1024 * 1024_u32).to_le_bytes());
+        image[entry + 24..entry + 32].fill(0);
+
+        let mut reader = std::io::Cursor::new(image);
+        reader.seek(SeekFrom::Start(META_DATA as u64)).unwrap();
+        let meta_data = MetaData::deserialize(&mut reader).unwrap();
+        assert!(meta_data.get_entry(unknown).is_none());
+        assert!(meta_data.get_entry(MetaData::VIRTUAL_DISK_SIZE).is_some());
+    }
+
+    #[test]
+    fn zero_entry_offset_rejected() {
+        const META_DATA: usize = 2 * 1024 * 1024;
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        // Offset of the virtual disk size entry
+        image[META_DATA + 32 + 32 + 16..META_DATA + 32 + 32 + 20].fill(0);
+
+        let mut reader = std::io::Cursor::new(image);
+        reader.seek(SeekFrom::Start(META_DATA as u64)).unwrap();
+        assert!(matches!(
+            MetaData::deserialize(&mut reader),
+            Err(VhdxError::MetadataEntryOffsetInvalid(0))
+        ));
+    }
+
+    #[test]
+    fn required_unknown_entry_is_error() {
+        const META_DATA: usize = 2 * 1024 * 1024;
+        let unknown = uuid!("0F0E0D0C0B0A09080706050403020100");
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        image[META_DATA + 10..META_DATA + 12].copy_from_slice(&6_u16.to_le_bytes());
+        let entry = META_DATA + 32 + 5 * 32;
+        image[entry..entry + 16].copy_from_slice(&unknown.to_bytes_le());
+        image[entry + 24..entry + 32].copy_from_slice(&[0x04, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut reader = std::io::Cursor::new(image);
+        reader.seek(SeekFrom::Start(META_DATA as u64)).unwrap();
+        assert!(matches!(
+            MetaData::deserialize(&mut reader),
+            Err(VhdxError::UnknownRequiredMetadataItem(id)) if id == unknown
+        ));
+    }
+
+    #[test]
+    fn entry_count_beyond_real_entries() {
+        const META_DATA: usize = 2 * 1024 * 1024;
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        image[META_DATA + 10..META_DATA + 12].copy_from_slice(&10_u16.to_le_bytes());
+        // Clear the stale entry following the five real ones
+        image[META_DATA + 32 + 5 * 32..META_DATA + 32 + 10 * 32].fill(0);
+
+        let mut reader = std::io::Cursor::new(image);
+        reader.seek(SeekFrom::Start(META_DATA as u64)).unwrap();
+        let meta_data = MetaData::deserialize(&mut reader).unwrap();
+        assert_eq!(5, meta_data.entries.len());
+        assert_eq!(4 * 1024 * 1024, meta_data.virtual_disk_size);
+    }
+
+    #[test]
+    fn entry_count_above_limit() {
+        const META_DATA: usize = 2 * 1024 * 1024;
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        image[META_DATA + 10..META_DATA + 12].copy_from_slice(&2048_u16.to_le_bytes());
+
+        let mut reader = std::io::Cursor::new(image);
+        reader.seek(SeekFrom::Start(META_DATA as u64)).unwrap();
+        assert!(matches!(
+            MetaData::deserialize(&mut reader),
+            Err(VhdxError::RTEntryCountError(2048))
+        ));
+    }
+
+    #[test]
+    fn missing_required_entry() {
+        const META_DATA: usize = 2 * 1024 * 1024;
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        // Frees the table entry of the virtual disk size
+        let entry = (0..5)
+            .map(|i| META_DATA + 32 + i * 32)
+            .find(|entry| image[*entry..*entry + 16] == MetaData::VIRTUAL_DISK_SIZE.to_bytes_le())
+            .unwrap();
+        image[entry..entry + 16].fill(0);
+
+        let mut reader = std::io::Cursor::new(image);
+        reader.seek(SeekFrom::Start(META_DATA as u64)).unwrap();
+        assert!(matches!(
+            MetaData::deserialize(&mut reader),
+            Err(VhdxError::MissingMetadataEntry("Virtual Disk Size"))
+        ));
+    }
+
+    #[test]
+    fn truncated_metadata() {
+        const META_DATA: usize = 2 * 1024 * 1024;
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut reader = std::io::Cursor::new(&image[..META_DATA + 100]);
+        reader.seek(SeekFrom::Start(META_DATA as u64)).unwrap();
+        assert!(matches!(
+            MetaData::deserialize(&mut reader),
+            Err(VhdxError::UnexpectedEof {
+                structure: "Metadata",
+                offset,
+            }) if offset == META_DATA as u64
+        ));
+    }
+}
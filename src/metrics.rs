@@ -0,0 +1,76 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::time::Duration;
+
+// Timings and IO counters collected while opening an image, only recorded when requested through
+// the open options.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ParseMetrics {
+    // File type identifier, both headers and both region tables
+    pub headers: Duration,
+    pub log: Duration,
+    pub meta_data: Duration,
+    pub bat: Duration,
+    // Total number of bytes handed out by the reader
+    pub bytes_read: u64,
+    // Number of read calls issued against the reader
+    pub reads: u64,
+}
+
+impl ParseMetrics {
+    pub fn total(&self) -> Duration {
+        self.headers + self.log + self.meta_data + self.bat
+    }
+}
+
+// Reader wrapper counting the bytes and read calls passing through it.
+pub(crate) struct CountingReader<'a, R> {
+    inner: &'a mut R,
+    pub(crate) bytes_read: u64,
+    pub(crate) reads: u64,
+}
+
+impl<'a, R> CountingReader<'a, R> {
+    pub(crate) fn new(inner: &'a mut R) -> Self {
+        Self {
+            inner,
+            bytes_read: 0,
+            reads: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes_read += read as u64;
+        self.reads += 1;
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for CountingReader<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    #[test]
+    fn counts_bytes_and_reads() {
+        let mut cursor = Cursor::new(vec![0u8; 32]);
+        let mut reader = CountingReader::new(&mut cursor);
+        let mut buffer = [0; 12];
+        reader.read_exact(&mut buffer).unwrap();
+        reader.seek(SeekFrom::Start(28)).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+
+        assert_eq!(16, reader.bytes_read);
+        assert_eq!(4, rest.len());
+    }
+}
@@ -36,7 +36,7 @@ pub fn t_sign_u32(buffer: &[u8]) -> IResult<&[u8], Signature, VhdxParseError<&[u
 
 pub fn t_guid(buffer: &[u8]) -> nom::IResult<&[u8], Uuid, VhdxParseError<&[u8]>> {
     map_res(take(16usize), |bytes: &[u8]| {
-        Ok(Builder::from_slice_le(bytes)?.into_uuid())
+        Builder::from_slice_le(bytes).map(Builder::into_uuid)
     })(buffer)
 }
 
@@ -57,15 +57,12 @@ pub fn t_u16(buffer: &[u8]) -> IResult<&[u8], u16, VhdxParseError<&[u8]>> {
 }
 
 pub fn t_creator(buffer: &[u8]) -> IResult<&[u8], String, VhdxParseError<&[u8]>> {
-    map(take(512usize), |bytes: &[u8]| {
+    map_res(take(512usize), |bytes: &[u8]| {
         let bytes: Vec<u16> = bytes
             .chunks_exact(2)
             .map(|b: &[u8]| ((b[1] as u16) << 8) | (b[0] as u16))
             .collect();
         String::from_utf16(&bytes)
-            // Handle utf error
-            .unwrap()
-            .trim_end_matches(char::from(0))
-            .to_string()
+            .map(|creator| creator.trim_end_matches(char::from(0)).to_string())
     })(buffer)
 }
@@ -0,0 +1,536 @@
+use std::borrow::{Borrow, BorrowMut};
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+
+use crate::bat::{payload_bat_index, BatEntryState};
+use crate::error::VhdxError;
+use crate::vhdx::Vhdx;
+
+// Order in which an export reads the present blocks from the image. Virtual order follows the
+// disk and seeks back and forth when blocks are scattered through the file, physical order first
+// stages every fully present block in ascending file offset order, trading memory for sequential
+// reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExportOrder {
+    Virtual,
+    Physical,
+}
+
+// Byte stream over the logical contents of a virtual disk. Present data is read from the image,
+// blocks deferred to a parent are read from the parent when the chain was opened and everything
+// else reads as zeros.
+// The reader either owns the image or borrows it, see into_sector_reader and reader.
+#[derive(Debug)]
+pub struct VhdxReader<T, V = Vhdx<T>> {
+    vhdx: V,
+    position: u64,
+    disk: PhantomData<T>,
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Seek,
+{
+    pub fn into_sector_reader(self) -> VhdxReader<T> {
+        VhdxReader {
+            vhdx: self,
+            position: 0,
+            disk: PhantomData,
+        }
+    }
+
+    // Same as into_sector_reader but leaves the image usable once the reader is dropped
+    pub fn reader(&mut self) -> VhdxReader<T, &mut Vhdx<T>> {
+        VhdxReader {
+            vhdx: self,
+            position: 0,
+            disk: PhantomData,
+        }
+    }
+
+    // Reads from the virtual offset up to the end of the block (or sector for partially present
+    // blocks), returns the number of bytes placed in the buffer.
+    pub(crate) fn read_virtual(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let virtual_disk_size = self.meta_data.virtual_disk_size as u64;
+        if offset >= virtual_disk_size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let block_size = self.meta_data.file_parameters.block_size as u64;
+        let block = offset / block_size;
+        let within = offset % block_size;
+        let mut len = (buf.len() as u64)
+            .min(virtual_disk_size - offset)
+            .min(block_size - within);
+
+        let entry = self
+            .bat_table
+            .get(payload_bat_index(block, self.meta_data.chunk_ratio) as usize)
+            .copied();
+        let present = match entry.map(|entry| entry.state()) {
+            Some(BatEntryState::FullyPresent) => true,
+            Some(BatEntryState::PartiallyPresent) => {
+                let sector_size = self.meta_data.logical_sector_size as u64;
+                len = len.min(sector_size - offset % sector_size);
+                self.sectors_present(offset, offset + len)
+                    .map_err(io::Error::other)?
+            }
+            _ => false,
+        };
+
+        let buf = &mut buf[..len as usize];
+        match (present, entry.and_then(|entry| entry.file_offset())) {
+            (true, Some(file_offset)) => {
+                self.file.seek(SeekFrom::Start(file_offset + within))?;
+                self.file.read_exact(buf)?;
+                Ok(buf.len())
+            }
+            _ => {
+                let deferred = matches!(
+                    entry.map(|entry| entry.state()),
+                    Some(BatEntryState::NotPresent | BatEntryState::PartiallyPresent)
+                );
+                match self.parent.as_mut() {
+                    Some(parent) if deferred => parent.read_virtual(offset, buf),
+                    _ => {
+                        buf.fill(0);
+                        Ok(buf.len())
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<T> Vhdx<T>
+where
+    T: Read + Seek,
+{
+    // Writes the complete virtual disk as a flat raw image, returns the number of bytes written.
+    pub fn export_raw<W: Write>(&mut self, out: &mut W) -> Result<u64, VhdxError> {
+        self.export_raw_in(out, ExportOrder::Virtual)
+    }
+
+    // Same output as export_raw, but the present blocks are read in the order they are stored in
+    // the file, which is faster on media where seeking is expensive.
+    pub fn export_raw_physical<W: Write>(&mut self, out: &mut W) -> Result<u64, VhdxError> {
+        self.export_raw_in(out, ExportOrder::Physical)
+    }
+
+    fn export_raw_in<W: Write>(
+        &mut self,
+        out: &mut W,
+        order: ExportOrder,
+    ) -> Result<u64, VhdxError> {
+        let virtual_disk_size = self.meta_data.virtual_disk_size as u64;
+        let block_size = self.meta_data.file_parameters.block_size as u64;
+        let block_length =
+            |block: u64| block_size.min(virtual_disk_size - block * block_size) as usize;
+
+        let mut staged = HashMap::new();
+        if order == ExportOrder::Physical {
+            let mut present: Vec<(u64, u64)> = self
+                .bat_block_map()
+                .into_iter()
+                .filter(|(block, _, _)| {
+                    self.bat_table
+                        .get(payload_bat_index(*block, self.meta_data.chunk_ratio) as usize)
+                        .is_some_and(|entry| entry.state() == BatEntryState::FullyPresent)
+                })
+                .filter_map(|(block, offset, _)| offset.map(|offset| (block, offset)))
+                .collect();
+            present.sort_by_key(|(_, offset)| *offset);
+
+            for (block, offset) in present {
+                let mut buffer = vec![0; block_length(block)];
+                self.file.seek(SeekFrom::Start(offset))?;
+                self.file.read_exact(&mut buffer)?;
+                staged.insert(block, buffer);
+            }
+        }
+
+        let mut written = 0;
+        for block in 0..self.meta_data.payload_blocks_count {
+            let buffer = match staged.remove(&block) {
+                Some(buffer) => buffer,
+                None => {
+                    let mut buffer = vec![0; block_length(block)];
+                    self.read_virtual_exact(block * block_size, &mut buffer)?;
+                    buffer
+                }
+            };
+            out.write_all(&buffer)?;
+            written += buffer.len() as u64;
+        }
+
+        Ok(written)
+    }
+
+    // Fills the buffer from an arbitrary virtual offset, crossing block boundaries as needed. Reads
+    // stop at the end of the virtual disk, the number of bytes placed in the buffer is returned.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, VhdxError> {
+        let virtual_disk_size = self.meta_data.virtual_disk_size as u64;
+        let available = virtual_disk_size
+            .saturating_sub(offset)
+            .min(buf.len() as u64);
+        let buf = &mut buf[..available as usize];
+        self.read_virtual_exact(offset, buf)?;
+        Ok(buf.len())
+    }
+
+    // Reads one logical sector of the virtual disk. Sectors stored in this file are read from
+    // their payload block, sectors deferred to an opened parent come from the parent and every
+    // other sector reads as zeros.
+    pub fn read_sector(&mut self, lba: u64) -> Result<Vec<u8>, VhdxError> {
+        let sector_size = self.meta_data.logical_sector_size.bytes();
+        let offset = lba
+            .checked_mul(sector_size)
+            .filter(|offset| *offset < self.meta_data.virtual_disk_size as u64)
+            .ok_or(VhdxError::LbaOutOfRange(lba))?;
+
+        let mut sector = vec![0; sector_size as usize];
+        self.read_virtual_exact(offset, &mut sector)?;
+        Ok(sector)
+    }
+
+    // Reads from the parent at the logical sector regardless of what this disk stores for it,
+    // which allows checking how a chain resolves independently of the sector bitmaps.
+    pub fn read_sector_from_parent(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), VhdxError> {
+        let sector_size = self.meta_data.logical_sector_size.bytes();
+        let parent = match self.parent.as_mut() {
+            Some(parent) if self.meta_data.file_parameters.has_parent => parent,
+            _ => return Err(VhdxError::NoParent),
+        };
+
+        let len = buf.len() as u64;
+        let offset = lba
+            .checked_mul(sector_size)
+            .filter(|offset| {
+                offset
+                    .checked_add(len)
+                    .is_some_and(|end| end <= parent.meta_data.virtual_disk_size as u64)
+            })
+            .ok_or(VhdxError::RangeOutOfBounds(
+                lba.saturating_mul(sector_size),
+                len,
+            ))?;
+        parent.read_virtual_exact(offset, buf)?;
+        Ok(())
+    }
+
+    // Writes len bytes of the virtual disk starting at the offset, for exports of only a part of
+    // the disk such as the partition table and a single partition.
+    pub fn export_raw_range<W: Write>(
+        &mut self,
+        out: &mut W,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), VhdxError> {
+        offset
+            .checked_add(len)
+            .filter(|end| *end <= self.meta_data.virtual_disk_size as u64)
+            .ok_or(VhdxError::RangeOutOfBounds(offset, len))?;
+
+        let mut buffer = vec![0; len.min(Vhdx::MB) as usize];
+        let mut position = offset;
+        while position < offset + len {
+            let chunk = (offset + len - position).min(buffer.len() as u64) as usize;
+            self.read_virtual_exact(position, &mut buffer[..chunk])?;
+            out.write_all(&buffer[..chunk])?;
+            position += chunk as u64;
+        }
+        Ok(())
+    }
+
+    fn read_virtual_exact(&mut self, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let read = self.read_virtual(offset, buf)?;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            offset += read as u64;
+            buf = &mut buf[read..];
+        }
+        Ok(())
+    }
+}
+
+impl<T, V> VhdxReader<T, V> {
+    pub fn into_inner(self) -> V {
+        self.vhdx
+    }
+}
+
+impl<T, V> Read for VhdxReader<T, V>
+where
+    T: Read + Seek,
+    V: BorrowMut<Vhdx<T>>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self
+            .vhdx
+            .borrow_mut()
+            .read_at(self.position, buf)
+            .map_err(|e| match e {
+                VhdxError::IoError(e) => e,
+                e => io::Error::other(e),
+            })?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+// Seeking past the end is allowed like on a file, reads there return no data
+impl<T, V> Seek for VhdxReader<T, V>
+where
+    V: Borrow<Vhdx<T>>,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(delta) => (self.vhdx.borrow().meta_data.virtual_disk_size as u64, delta),
+            SeekFrom::Current(delta) => (self.position, delta),
+        };
+
+        self.position = base.checked_add_signed(delta).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+    use std::path::Path;
+
+    const MB: usize = 1024 * 1024;
+
+    // The payload blocks of the fixture are empty, fill them with a pattern unique per sector
+    fn patterned_fixture() -> Vec<u8> {
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        image[4 * MB..8 * MB]
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b = (i / 512 + i % 251) as u8);
+        image
+    }
+
+    #[test]
+    fn read_first_8kb() {
+        let image = patterned_fixture();
+        let vhdx = Vhdx::from_reader(Cursor::new(image.clone())).unwrap();
+        let mut reader = vhdx.into_sector_reader();
+
+        let mut buffer = vec![0; 8192];
+        reader.read_exact(&mut buffer).unwrap();
+        assert_eq!(&image[4 * MB..4 * MB + 8192], &buffer[..]);
+
+        // Crosses from the first into the second block
+        reader.seek(SeekFrom::Start(2 * MB as u64 - 4096)).unwrap();
+        reader.read_exact(&mut buffer).unwrap();
+        assert_eq!(&image[6 * MB - 4096..6 * MB + 4096], &buffer[..]);
+
+        assert_eq!(4 * MB as u64, reader.seek(SeekFrom::End(0)).unwrap());
+        assert_eq!(0, reader.read(&mut buffer).unwrap());
+    }
+
+    #[test]
+    fn read_sector_by_lba() {
+        let image = patterned_fixture();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image.clone())).unwrap();
+
+        assert_eq!(
+            &image[4 * MB..4 * MB + 512],
+            &vhdx.read_sector(0).unwrap()[..]
+        );
+        // First sector of the second block
+        let lba = (2 * MB / 512) as u64;
+        assert_eq!(
+            &image[6 * MB..6 * MB + 512],
+            &vhdx.read_sector(lba).unwrap()[..]
+        );
+
+        let last = (4 * MB / 512) as u64 - 1;
+        assert_eq!(512, vhdx.read_sector(last).unwrap().len());
+        assert!(matches!(
+            vhdx.read_sector(last + 1),
+            Err(VhdxError::LbaOutOfRange(lba)) if lba == last + 1
+        ));
+
+        // A block that is not present reads as zeros
+        vhdx.bat_table[1] = crate::bat::BatEntry::new(BatEntryState::NotPresent, 0);
+        assert!(vhdx.read_sector(lba).unwrap().iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn read_at_crosses_blocks() {
+        let image = patterned_fixture();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image.clone())).unwrap();
+        // Only the first block is allocated
+        vhdx.bat_table[1] = crate::bat::BatEntry::new(BatEntryState::NotPresent, 0);
+
+        // Starts 1000 bytes before the end of the first block and ends within the second
+        let offset = 2 * MB as u64 - 1000;
+        let mut buffer = vec![0xFF; 3000];
+        assert_eq!(3000, vhdx.read_at(offset, &mut buffer).unwrap());
+        assert_eq!(&image[6 * MB - 1000..6 * MB], &buffer[..1000]);
+        assert!(buffer[1000..].iter().all(|b| *b == 0));
+
+        // Short read at the end of the virtual disk
+        let mut buffer = vec![0xFF; 4096];
+        assert_eq!(
+            1024,
+            vhdx.read_at(4 * MB as u64 - 1024, &mut buffer).unwrap()
+        );
+        assert_eq!(0, vhdx.read_at(4 * MB as u64, &mut buffer).unwrap());
+    }
+
+    #[test]
+    fn borrowed_reader_leaves_image_usable() {
+        let image = patterned_fixture();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image.clone())).unwrap();
+
+        let mut reader = vhdx.reader();
+        assert_eq!(
+            4 * MB as u64 - 512,
+            reader.seek(SeekFrom::End(-512)).unwrap()
+        );
+        let mut buffer = vec![0; 1024];
+        assert_eq!(512, reader.read(&mut buffer).unwrap());
+        assert_eq!(&image[8 * MB - 512..8 * MB], &buffer[..512]);
+
+        // Past the end is a valid position without data
+        assert_eq!(
+            5 * MB as u64,
+            reader.seek(SeekFrom::Start(5 * MB as u64)).unwrap()
+        );
+        assert_eq!(0, reader.read(&mut buffer).unwrap());
+
+        assert_eq!(
+            &image[4 * MB..4 * MB + 512],
+            &vhdx.read_sector(0).unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn export_range_across_block_boundary() {
+        let image = patterned_fixture();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image.clone())).unwrap();
+        vhdx.bat_table[1] = crate::bat::BatEntry::new(BatEntryState::NotPresent, 0);
+
+        let mut out = Vec::new();
+        let offset = 2 * MB as u64 - 3 * MB as u64 / 2;
+        vhdx.export_raw_range(&mut out, offset, 2 * MB as u64)
+            .unwrap();
+        assert_eq!(2 * MB, out.len());
+        assert_eq!(&image[4 * MB + MB / 2..6 * MB], &out[..3 * MB / 2]);
+        assert!(out[3 * MB / 2..].iter().all(|b| *b == 0));
+
+        assert!(matches!(
+            vhdx.export_raw_range(&mut out, 3 * MB as u64, 2 * MB as u64),
+            Err(VhdxError::RangeOutOfBounds(_, _))
+        ));
+    }
+
+    #[test]
+    fn absent_blocks_read_as_zeros() {
+        let parent = Vhdx::new(&"test.vhdx").unwrap();
+        let mut child = Cursor::new(Vec::new());
+        Vhdx::new_differencing(&mut child, &parent, Path::new("test.vhdx")).unwrap();
+        child.set_position(0);
+
+        let mut reader = Vhdx::from_reader(child).unwrap().into_sector_reader();
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).unwrap();
+        assert_eq!(4 * MB, contents.len());
+        assert!(contents.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn read_sector_from_parent_bypasses_child() {
+        let parent_path =
+            std::env::temp_dir().join(format!("vhdx-rs-{}-parent-read.vhdx", std::process::id()));
+        let image = patterned_fixture();
+        std::fs::write(&parent_path, &image).unwrap();
+        let parent = Vhdx::new(&parent_path);
+        std::fs::remove_file(&parent_path).unwrap();
+        let parent = parent.unwrap();
+
+        let mut child = Cursor::new(Vec::new());
+        Vhdx::new_differencing(&mut child, &parent, &parent_path).unwrap();
+        child.set_position(0);
+        let mut child = Vhdx::from_reader(child).unwrap();
+
+        let mut buffer = vec![0; 512];
+        assert!(matches!(
+            child.read_sector_from_parent(0, &mut buffer),
+            Err(VhdxError::NoParent)
+        ));
+
+        // Point the first block of the child at its own file start, the parent must still be read
+        child.parent = Some(Box::new(parent));
+        child.bat_table[0] = crate::bat::BatEntry::new(BatEntryState::FullyPresent, 0);
+        child.read_sector_from_parent(3, &mut buffer).unwrap();
+        assert_eq!(&image[4 * MB + 3 * 512..4 * MB + 4 * 512], &buffer[..]);
+
+        assert!(matches!(
+            child.read_sector_from_parent((4 * MB / 512) as u64, &mut buffer),
+            Err(VhdxError::RangeOutOfBounds(_, 512))
+        ));
+    }
+
+    #[test]
+    fn export_zero_fills_missing_blocks() {
+        // Mark the first block as not present, the second keeps its data
+        let mut image = patterned_fixture();
+        let bat = 3 * MB;
+        image[bat..bat + 8].fill(0);
+
+        let mut raw = Vec::new();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        let written = vhdx.export_raw(&mut raw).unwrap();
+
+        assert_eq!(vhdx.virtual_size(), written);
+        assert!(raw[..2 * MB].iter().all(|b| *b == 0));
+        assert_eq!(image_block(1), &raw[2 * MB..]);
+    }
+
+    fn image_block(index: usize) -> Vec<u8> {
+        patterned_fixture()[(4 + 2 * index) * MB..(6 + 2 * index) * MB].to_vec()
+    }
+
+    #[test]
+    fn physical_and_virtual_export_match() {
+        // Swap the two blocks in the file so virtual and physical order differ
+        let mut image = patterned_fixture();
+        let bat = 3 * MB;
+        let (first, second) = image[bat..bat + 16].split_at_mut(8);
+        first.swap_with_slice(second);
+
+        let mut virtual_order = Vec::new();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        let written = vhdx.export_raw(&mut virtual_order).unwrap();
+        let mut physical_order = Vec::new();
+        vhdx.export_raw_physical(&mut physical_order).unwrap();
+
+        assert_eq!(4 * MB as u64, written);
+        assert_eq!(virtual_order, physical_order);
+        assert_eq!(image_block(1), &virtual_order[..2 * MB]);
+
+        let mut streamed = Vec::new();
+        vhdx.into_sector_reader()
+            .read_to_end(&mut streamed)
+            .unwrap();
+        assert_eq!(virtual_order, streamed);
+    }
+}
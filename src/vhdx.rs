@@ -1,125 +1,321 @@
 #![allow(dead_code)]
 
-use crate::bat::BatEntry;
+use crate::bat::{
+    is_sector_bitmap_index, payload_bat_index, sector_bitmap_bat_index, BatEntry, BatEntryState,
+    BlockInfo, SectorBitmap,
+};
 use crate::log::LogSequence;
-use crate::vhdx_header::Header;
+use crate::meta_data::{DiskType, FileParameters, LocatorTypeEntry, ParentLocator, SectorSize};
+use crate::metrics::{CountingReader, ParseMetrics};
+use crate::vhdx_header::{FileTypeIdentifier, Header, RTEntry, RegionTable};
+use crate::{crc32c_stream, Crc32, DeSerialise, Serialise, Validation};
 use crate::{
     error::{Result, VhdxError},
-    log::{Log, LogEntry},
+    log::{Descriptor, Log, LogEntry, LogHeader, LogSummary, ReplayResult},
     meta_data::MetaData,
     parse_utils::t_sign_u32,
     vhdx_header::{KnowRegion, VhdxHeader},
     Signature,
 };
-use crate::{Crc32, DeSerialise, Validation};
+use crc::CRC_32_ISO_HDLC;
 use nom::combinator::peek;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
-use std::path::Path;
-use uuid::Uuid;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use uuid::{Builder, Uuid};
 
 #[derive(Debug)]
-pub struct Vhdx {
-    pub(crate) file: File,
+pub struct Vhdx<T = File> {
+    pub(crate) file: T,
+    pub(crate) file_size: u64,
     pub header: VhdxHeader,
     pub log: Log,
     pub meta_data: MetaData,
     pub bat_table: Vec<BatEntry>,
+    // Opened parent of a differencing disk, only set by open_chain
+    pub parent: Option<Box<Vhdx>>,
+    pub(crate) metrics: Option<ParseMetrics>,
+    // Set when the image was opened without write access
+    pub(crate) read_only: bool,
+    // Which of the two headers (1 or 2) was current when the image was opened
+    pub(crate) current_header: u32,
+    // Non-fatal issues found while opening with Strictness::Normal
+    pub(crate) warnings: Vec<VhdxError>,
+}
+
+// Settings applied while opening an image, the defaults match Vhdx::new.
+#[derive(Debug, Clone)]
+pub struct VhdxOptions {
+    // Record per phase timings and the number of bytes read, see Vhdx::metrics
+    pub collect_metrics: bool,
+    // Skips the 1MB placement checks of regions and the log so tests can use compact images,
+    // real images always satisfy them
+    pub relax_alignment: bool,
+    // Open the file without requesting write access
+    pub read_only: bool,
+    // How issues that don't stop the file from being read are treated
+    pub strictness: Strictness,
+    // Compare the stored checksums of the headers and region tables, when off only their
+    // signatures are checked. Log entries are always verified since their checksums decide
+    // which entries belong to the active sequence.
+    pub verify_checksums: bool,
+    // Replay a pending log when the file is opened writable
+    pub replay_log: bool,
+}
+
+impl Default for VhdxOptions {
+    fn default() -> Self {
+        Self {
+            collect_metrics: false,
+            relax_alignment: false,
+            read_only: false,
+            strictness: Strictness::default(),
+            verify_checksums: true,
+            replay_log: true,
+        }
+    }
+}
+
+// Level of spec conformance required when opening. Issues like nonzero reserved fields, unknown
+// optional regions and misaligned but readable offsets are ignored when lenient, kept as warnings
+// when normal and fail the open when strict.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Strictness {
+    Lenient,
+    #[default]
+    Normal,
+    Strict,
+}
+
+impl Strictness {
+    fn apply(self, issue: VhdxError, warnings: &mut Vec<VhdxError>) -> Result<(), VhdxError> {
+        match self {
+            Strictness::Lenient => Ok(()),
+            Strictness::Normal => {
+                warnings.push(issue);
+                Ok(())
+            }
+            Strictness::Strict => Err(issue),
+        }
+    }
 }
 
 impl Vhdx {
     pub(crate) const KB: u64 = 1024;
     pub(crate) const MB: u64 = Vhdx::KB * Vhdx::KB;
 
+    // The first region has to start at 1MB or later, so no valid image is smaller than that
+    pub fn minimum_vhdx_size() -> u64 {
+        Vhdx::MB
+    }
+
     pub fn new(path: &impl AsRef<Path>) -> Result<Self, VhdxError> {
-        let mut reader = File::options().read(true).write(true).open(path)?;
+        Vhdx::open_with(path, &VhdxOptions::default())
+    }
 
-        let header = VhdxHeader::deserialize(&mut reader)?;
-        let (header_no, h) = get_current_header(&header.header_1, &header.header_2)?;
-        h.validate()?;
+    // Opens the file with read permission only, the log is not replayed and every write path
+    // fails with ReadOnly, so the file is never modified
+    pub fn open_readonly(path: &impl AsRef<Path>) -> Result<Self, VhdxError> {
+        let options = VhdxOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        Vhdx::open_with(path, &options)
+    }
 
-        let _ = reader.seek(SeekFrom::Start(h.log_offset));
-        let mut log_entries = Vec::new();
-        let log_end = h.log_offset + h.log_length as u64;
-
-        while reader.stream_position()? != log_end {
-            let log_entry = LogEntry::deserialize(&mut reader)?;
-            log_entries.push(log_entry);
-
-            // peeking to see if there are any more logs
-            let mut buffer = [0; 4];
-            reader.read_exact(&mut buffer)?;
-            let mut peeker = peek(t_sign_u32);
-            let (_, signature) = peeker(&buffer)?;
-            match signature {
-                //if there are logs we back up and let the loop run again
-                Signature::Loge => {
-                    reader.seek(SeekFrom::Current(-4))?;
-                }
-                // Otherwise that was last entry we break
-                _ => break,
+    pub fn open_with(path: &impl AsRef<Path>, options: &VhdxOptions) -> Result<Self, VhdxError> {
+        let file = open_file(path.as_ref(), !options.read_only)?;
+        Vhdx::from_file_with(file, options)
+    }
+
+    // Opens read-write when possible and falls back to read-only when write access is denied,
+    // so read-only media can be inspected without the caller having to choose.
+    pub fn open_auto(path: &impl AsRef<Path>) -> Result<Self, VhdxError> {
+        Vhdx::open_auto_with(path.as_ref(), open_file)
+    }
+
+    fn open_auto_with<F>(path: &Path, opener: F) -> Result<Self, VhdxError>
+    where
+        F: Fn(&Path, bool) -> std::io::Result<File>,
+    {
+        let mut options = VhdxOptions::default();
+        let file = match opener(path, true) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                options.read_only = true;
+                opener(path, false)?
             }
-        }
+            Err(e) => return Err(e.into()),
+        };
+        Vhdx::from_file_with(file, &options)
+    }
 
-        let r = match header_no {
-            1 => &header.region_table_1,
-            2 => &header.region_table_2,
-            _ => panic!("Impossiburru"),
+    // Opens every .vhdx file directly inside the directory read-only and summarises it, a file
+    // that fails to open is reported next to its path instead of aborting the scan. Results are
+    // sorted by path.
+    pub fn scan_dir(dir: &Path) -> Vec<(PathBuf, Result<VhdxInfo, VhdxError>)> {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => return vec![(dir.to_path_buf(), Err(e.into()))],
         };
 
-        r.validate()?;
+        let mut results: Vec<_> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.is_file()
+                    && path
+                        .extension()
+                        .is_some_and(|ext| ext.eq_ignore_ascii_case("vhdx"))
+            })
+            .map(|path| {
+                let info = Vhdx::open_readonly(&path).map(|vhdx| vhdx.info());
+                (path, info)
+            })
+            .collect();
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+        results
+    }
 
-        let meta_data_info = &r
-            .table_entries
-            .get(&KnowRegion::MetaData)
-            .ok_or(VhdxError::MissingKnownRegion("MetaData"))?;
+    // Limit on the number of disks in a differencing chain, guards against locators that loop
+    pub(crate) const MAX_CHAIN_DEPTH: usize = 16;
 
-        let bat_table_info = &r
-            .table_entries
-            .get(&KnowRegion::Bat)
-            .ok_or(VhdxError::MissingKnownRegion("Bat"))?;
+    // Opens the disk and, for differencing disks, every parent referenced through the parent
+    // locator. Each parent must still carry the data_write_guid the child was linked against,
+    // otherwise the parent was modified after the child was created and the chain is broken.
+    pub fn open_chain(path: &impl AsRef<Path>) -> Result<Self, VhdxError> {
+        Vhdx::open_chain_at_depth(path.as_ref(), 0)
+    }
 
-        // Read MetaData
-        reader.seek(SeekFrom::Start(meta_data_info.file_offset))?;
-        let meta_data = MetaData::deserialize(&mut reader).unwrap();
+    fn open_chain_at_depth(path: &Path, depth: usize) -> Result<Self, VhdxError> {
+        if depth >= Vhdx::MAX_CHAIN_DEPTH {
+            return Err(VhdxError::ChainTooDeep(Vhdx::MAX_CHAIN_DEPTH));
+        }
 
-        // Read BAT Table
-        reader.seek(SeekFrom::Start(bat_table_info.file_offset))?;
-        let bat_table: Vec<BatEntry> = (0..meta_data.total_bat_entries_fixed_dynamic)
-            .map(|_| BatEntry::deserialize(&mut reader).unwrap())
-            .collect();
+        let mut vhdx = Vhdx::new(&path)?;
+        if !vhdx.meta_data.file_parameters.has_parent {
+            return Ok(vhdx);
+        }
 
-        let log = Log::new(log_entries);
-        let vhdx = Vhdx {
-            file: reader,
-            header,
-            log,
-            meta_data,
-            bat_table,
-        };
+        let locator = vhdx
+            .meta_data
+            .parent_locator
+            .as_ref()
+            .ok_or(VhdxError::MissingParentLocator)?;
+        let expected = locator
+            .parent_linkage()
+            .ok_or(VhdxError::MissingParentLocator)?;
+        let parent_path = resolve_parent_path(path, locator)?;
 
-        // vhdx.try_log_replay()?;
+        let parent = Vhdx::open_chain_at_depth(&parent_path, depth + 1)?;
+        let parent_header = parent.header();
+        if parent_header.data_write_guid != expected {
+            return Err(VhdxError::ParentLinkageMismatch {
+                expected,
+                found: parent_header.data_write_guid,
+            });
+        }
 
+        vhdx.parent = Some(Box::new(parent));
         Ok(vhdx)
     }
 
-    fn try_log_replay(&mut self) -> Result<(), VhdxError> {
-        if Uuid::is_nil(&self.header().log_guid) {
-            return Ok(());
-        }
+    // Creates a differencing disk on top of the parent, the child starts out with an empty BAT so
+    // every read falls through to the parent.
+    pub fn new_differencing<W, P>(
+        mut out: W,
+        parent: &Vhdx<P>,
+        parent_path: &Path,
+    ) -> Result<(), VhdxError>
+    where
+        W: Write + Seek,
+    {
+        let parent_header = parent.header();
+        let path_key = if parent_path.is_absolute() {
+            ParentLocator::ABSOLUTE_WIN32_PATH
+        } else {
+            ParentLocator::RELATIVE_PATH
+        };
+        let parent_locator = ParentLocator::new(vec![
+            (
+                ParentLocator::PARENT_LINKAGE.to_string(),
+                LocatorTypeEntry::Guid(parent_header.data_write_guid),
+            ),
+            (
+                path_key.to_string(),
+                LocatorTypeEntry::Path(parent_path.to_string_lossy().into_owned()),
+            ),
+        ]);
 
-        let _active_log = Vhdx::try_get_log_sequence(&self.log.log_entries);
+        let parent_meta_data = &parent.meta_data;
+        let meta_data = MetaData::create(
+            FileParameters {
+                block_size: parent_meta_data.file_parameters.block_size,
+                leave_block_allocated: false,
+                has_parent: true,
+            },
+            parent_meta_data.virtual_disk_size,
+            new_guid(),
+            parent_meta_data.logical_sector_size,
+            parent_meta_data.physical_sector_size,
+            Some(parent_locator),
+        );
+        let bat_entries = meta_data.bat_entry_count();
 
-        Ok(())
+        write_new_image(&mut out, &meta_data, bat_entries)
     }
 
-    fn header(&self) -> &Header {
-        &self.header.header_1
+    // Creates an empty dynamic disk at the path and opens it, every block starts out not present
+    // so the disk reads as zeros.
+    pub fn create_dynamic(
+        path: &impl AsRef<Path>,
+        virtual_size: u64,
+        block_size: u32,
+        sector_size: SectorSize,
+    ) -> Result<Self, VhdxError> {
+        let block_size = block_size as u64;
+        if !(Vhdx::MB..=256 * Vhdx::MB).contains(&block_size) || !block_size.is_power_of_two() {
+            return Err(VhdxError::BlockSizeError(block_size));
+        }
+        if virtual_size == 0 {
+            return Err(VhdxError::NotAllowedToBeZero("Virtual Disk Size"));
+        }
+        if !virtual_size.is_multiple_of(sector_size.bytes()) {
+            return Err(VhdxError::VirtualSizeNotSectorAligned(
+                virtual_size,
+                sector_size.bytes(),
+            ));
+        }
+
+        let meta_data = MetaData::create(
+            FileParameters {
+                block_size: block_size as usize,
+                leave_block_allocated: false,
+                has_parent: false,
+            },
+            virtual_size as usize,
+            new_guid(),
+            sector_size,
+            SectorSize::Sector4096,
+            None,
+        );
+        let bat_entries = meta_data.bat_entry_count();
+
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path.as_ref())?;
+        write_new_image(&mut file, &meta_data, bat_entries)?;
+        Vhdx::from_file_with(file, &VhdxOptions::default())
     }
 
-    pub(crate) fn try_get_log_sequence(
-        log_entries: &Vec<LogEntry>,
-    ) -> Result<LogSequence, VhdxError> {
+    pub(crate) fn try_get_log_sequence(log_entries: &[LogEntry]) -> Result<LogSequence, VhdxError> {
         let mut active = LogSequence {
             sequence_number: 0,
             entries: Vec::new(),
@@ -128,8 +324,6 @@ impl Vhdx {
         };
 
         let mut read_items = 0;
-        let mut current_head_offset = 0;
-        let mut seq_tail_offset = 0;
 
         loop {
             let mut candidate = LogSequence {
@@ -139,26 +333,23 @@ impl Vhdx {
                 tail_value: 0,
             };
 
-            candidate.tail_value = seq_tail_offset;
-
-            for (i, entry) in log_entries[read_items..].iter().enumerate() {
+            for entry in log_entries[read_items..].iter() {
+                read_items += 1;
+                // An invalid entry ends the candidate, the next one starts after it
                 if entry.validate().is_err() {
-                    read_items = i;
                     break;
                 }
 
                 if candidate.is_empty() {
                     candidate.sequence_number = entry.header.seq_number;
                     candidate.entries.push(entry.clone());
-                    candidate.head_value = current_head_offset;
-                } else if entry.header.seq_number == candidate.sequence_number + 1 {
+                    candidate.tail_value = entry.log_offset;
+                    candidate.head_value = entry.log_offset;
+                } else if Some(entry.header.seq_number) == candidate.sequence_number.checked_add(1)
+                {
                     candidate.entries.push(entry.clone());
-                    candidate.head_value = current_head_offset;
+                    candidate.head_value = entry.log_offset;
                 }
-
-                seq_tail_offset += entry.header.entry_length as u64;
-                current_head_offset += entry.header.entry_length as u64;
-                read_items += 1;
             }
 
             // Step 4
@@ -179,49 +370,2934 @@ impl Vhdx {
 
         Ok(active)
     }
+}
 
-    fn peek_signature(&mut self) -> Result<Signature, VhdxError> {
-        let mut buffer = [0; 4];
-        self.file.read_exact(&mut buffer)?;
-        let mut peeker = peek(t_sign_u32);
-        let (_, signature) = peeker(&buffer)?;
-        self.file.seek(SeekFrom::Current(-4))?;
-        Ok(signature)
+impl<T> Vhdx<T> {
+    // The header with the highest sequence number among the valid ones, determined on open
+    pub fn header(&self) -> &Header {
+        if self.current_header == 2 {
+            &self.header.header_2
+        } else {
+            &self.header.header_1
+        }
+    }
+
+    // Application that created the file as recorded in the file type identifier
+    pub fn creator(&self) -> &str {
+        self.header.fti().creator()
+    }
+
+    // Which header (1 or 2) is in use, it changes whenever the headers are updated
+    pub fn active_header_index(&self) -> u32 {
+        self.current_header
+    }
+
+    // Sequence numbers of header 1 and header 2 as stored, including a header that failed
+    // validation
+    pub fn header_sequence_numbers(&self) -> (u64, u64) {
+        (
+            self.header.header_1.sequence_number(),
+            self.header.header_2.sequence_number(),
+        )
+    }
+
+    fn header_mut(&mut self) -> &mut Header {
+        if self.current_header == 2 {
+            &mut self.header.header_2
+        } else {
+            &mut self.header.header_1
+        }
+    }
+
+    // Offset within the log, sequence number and tail of the next entry. Without a log guid or an
+    // active sequence the log is considered empty and a new sequence starts at the log start.
+    fn next_log_entry_position(&self) -> Result<(u64, u64, u64), VhdxError> {
+        let sequence = &self.log.log_sequence;
+        let position = match sequence.entries.last() {
+            Some(last) if !self.header().log_guid.is_nil() => (
+                (sequence.head_value + last.entry_length() as u64)
+                    .checked_rem(self.header().log_length as u64)
+                    .ok_or(VhdxError::NotAllowedToBeZero("Header Log Length"))?,
+                last.sequence_number().saturating_add(1),
+                sequence.tail_value,
+            ),
+            _ => {
+                let seq_number = self
+                    .log
+                    .log_entries
+                    .iter()
+                    .map(LogEntry::sequence_number)
+                    .max()
+                    .unwrap_or(0);
+                (0, seq_number.saturating_add(1), 0)
+            }
+        };
+        Ok(position)
+    }
+
+    // Location of a region as declared by the region table in use
+    pub fn region(&self, region: KnowRegion) -> Option<&RTEntry> {
+        get_current_region_table(
+            &self.header.region_table_1,
+            &self.header.region_table_2,
+            true,
+        )
+        .ok()?
+        .table_entries
+        .get(&region)
+    }
+
+    // The BAT and metadata regions have to start on a 1MB boundary after the first 1MB, which
+    // holds the headers and region tables. Images opened with relax_alignment may break this.
+    pub fn validate_against_spec_offsets(&self) -> Result<(), VhdxError> {
+        for (name, region) in [("Bat", KnowRegion::Bat), ("MetaData", KnowRegion::MetaData)] {
+            let entry = self
+                .region(region)
+                .ok_or(VhdxError::MissingKnownRegion(name))?;
+            if entry.file_offset < Vhdx::MB {
+                return Err(VhdxError::RegionInHeaderArea(name, entry.file_offset));
+            }
+            if !entry.file_offset.is_multiple_of(Vhdx::MB) {
+                return Err(VhdxError::NotDivisbleByMB(
+                    "Region File Offset",
+                    entry.file_offset,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Fixed, dynamic or differencing as declared by the file parameters
+    pub fn disk_type(&self) -> DiskType {
+        self.meta_data.file_parameters.disk_type()
+    }
+
+    // Summary of the most commonly needed properties of the disk
+    pub fn info(&self) -> VhdxInfo {
+        VhdxInfo {
+            disk_type: self.disk_type(),
+            virtual_disk_id: self.meta_data.virtual_disk_id,
+            virtual_disk_size: self.meta_data.virtual_disk_size as u64,
+            block_size: self.meta_data.file_parameters.block_size as u64,
+            logical_sector_size: self.meta_data.logical_sector_size.bytes(),
+            physical_sector_size: self.meta_data.physical_sector_size.bytes(),
+            file_size: self.file_size,
+        }
+    }
+
+    /// Size in bytes of a payload block, the unit in which the BAT maps the virtual disk to the
+    /// file. Always a power of two between 1MB and 256MB.
+    pub fn block_size(&self) -> usize {
+        self.meta_data.file_parameters.block_size
+    }
+
+    /// Identifier of the virtual disk, it stays the same when the file is copied or converted.
+    pub fn disk_id(&self) -> Uuid {
+        self.meta_data.virtual_disk_id
+    }
+
+    /// Sector size in bytes the virtual disk reports to the guest, either 512 or 4096.
+    pub fn logical_sector_size(&self) -> u32 {
+        self.meta_data.logical_sector_size.bytes() as u32
+    }
+
+    /// Sector size in bytes of the medium the virtual disk claims to be on, either 512 or 4096.
+    pub fn physical_sector_size(&self) -> u32 {
+        self.meta_data.physical_sector_size.bytes() as u32
+    }
+
+    /// Number of payload blocks per sector bitmap block. In the BAT every `chunk_ratio` payload
+    /// block entries are followed by the entry of the sector bitmap block covering them, so the
+    /// number of entries is roughly the number of payload blocks plus one per chunk.
+    ///
+    /// ```
+    /// use vhdx_rs::vhdx::Vhdx;
+    ///
+    /// let vhdx = Vhdx::new(&"test.vhdx").unwrap();
+    /// let payload_blocks = vhdx.meta_data.payload_blocks_count;
+    /// let total_bat_entries = payload_blocks + (payload_blocks - 1) / vhdx.chunk_ratio();
+    /// assert_eq!(total_bat_entries, vhdx.bat_table.len() as u64);
+    /// ```
+    pub fn chunk_ratio(&self) -> u64 {
+        self.meta_data.chunk_ratio
+    }
+
+    // Opaque token for incremental backups, the data_write_guid of the current header. It is
+    // replaced on the first user visible modification after the file is opened, so a token that
+    // differs from the stored one means the virtual disk contents may have changed since.
+    pub fn change_token(&self) -> Uuid {
+        self.header().data_write_guid
+    }
+
+    // File offset the next log entry is written to, directly after the last entry of the active
+    // sequence wrapping around at the end of the log, or the start of the log when it is empty.
+    pub fn log_head_offset(&self) -> Result<u64, VhdxError> {
+        let (head, _, _) = self.next_log_entry_position()?;
+        Ok(self.header().log_offset + head)
+    }
+
+    // Summary of the parsed log, everything a log inspection command needs in one call
+    pub fn log_summary(&self) -> LogSummary {
+        let log_guid = self.header().log_guid;
+        let entries = &self.log.log_entries;
+        let valid_entries = entries
+            .iter()
+            .filter(|entry| {
+                !log_guid.is_nil() && entry.header.log_guid == log_guid && entry.validate().is_ok()
+            })
+            .count();
+        let sequence = &self.log.log_sequence;
+
+        LogSummary {
+            log_guid,
+            entries: entries.len(),
+            active_sequence_number: sequence.sequence_number,
+            head_offset: sequence.head_value,
+            tail_offset: sequence.tail_value,
+            valid_entries,
+            invalid_entries: entries.len() - valid_entries,
+            descriptor_counts: entries
+                .iter()
+                .map(|entry| entry.header.descript_count)
+                .collect(),
+        }
+    }
+
+    // Entries of the active sequence are only valid for replay when written with the log guid of
+    // the current header. A nil log guid means the log is not in use, so there is nothing to check.
+    pub fn validate_log_guids(&self) -> Result<(), VhdxError> {
+        let expected = self.header().log_guid;
+        if expected.is_nil() {
+            return Ok(());
+        }
+
+        match self
+            .log
+            .log_sequence
+            .entries
+            .iter()
+            .find(|entry| entry.header.log_guid != expected)
+        {
+            Some(entry) => Err(VhdxError::LogGuidMismatch {
+                expected,
+                found: entry.header.log_guid,
+            }),
+            None => Ok(()),
+        }
     }
 }
 
-#[allow(clippy::if_same_then_else)]
-fn get_current_header<'a>(h1: &'a Header, h2: &'a Header) -> Result<(u32, &'a Header), VhdxError> {
-    let r1 = check_sign_and_crc(h1);
-    let r2 = check_sign_and_crc(h2);
+impl<T> Vhdx<T>
+where
+    T: Read + Write + Seek,
+{
+    // Persists the in memory header by writing it with the next sequence number to the slot that
+    // is not current. Should the write tear, the current header is still intact, once it is on
+    // disk the written slot becomes the current header.
+    pub fn flush(&mut self) -> Result<(), VhdxError> {
+        if self.read_only {
+            return Err(VhdxError::ReadOnly);
+        }
+        self.file.flush()?;
+
+        let mut header = self.header().clone();
+        header.seq_number = header.seq_number.saturating_add(1);
+        let (slot, offset) = if self.current_header == 1 {
+            (2, Vhdx::KB * 128)
+        } else {
+            (1, Vhdx::KB * 64)
+        };
 
-    let current = if r1.is_err() && r2.is_err() {
-        // TODO: Better error handling
-        return Err(VhdxError::VhdxHeaderError);
-    } else if r1.is_err() && r2.is_ok() {
-        (2, h2)
-    } else if r1.is_ok() && r2.is_err() {
-        (1, h1)
-    } else if h1.sequence_number() > h2.sequence_number() {
-        (1, h1)
-    } else {
-        (2, h2)
-    };
-    Ok(current)
+        self.file.seek(SeekFrom::Start(offset))?;
+        header.serialize(&mut self.file)?;
+        self.file.flush()?;
+
+        header.checksum = header.crc32();
+        if slot == 1 {
+            self.header.header_1 = header;
+        } else {
+            self.header.header_2 = header;
+        }
+        self.current_header = slot;
+        Ok(())
+    }
+
+    // Assigns a new file write guid and persists it with the next header update, which has to
+    // happen before the first modification of the file after it was opened.
+    pub fn regenerate_write_guid(&mut self) -> Result<(), VhdxError> {
+        if self.read_only {
+            return Err(VhdxError::ReadOnly);
+        }
+        self.header_mut().file_write_guid = new_guid();
+        self.flush()
+    }
+
+    // Opens a writable image, a log left behind by an interrupted update is replayed first and
+    // the image is parsed again since the replay may have changed any structure.
+    pub(crate) fn from_file_with(file: T, options: &VhdxOptions) -> Result<Self, VhdxError> {
+        let mut vhdx = Vhdx::from_reader_with(file, options)?;
+        if options.read_only || !options.replay_log || vhdx.replay_log()?.is_none() {
+            return Ok(vhdx);
+        }
+        Vhdx::from_reader_with(vhdx.file, options)
+    }
+
+    // Applies the active log sequence to the file and marks the log as no longer in use, returns
+    // what was replayed or None when the log was empty. Only entries written with the log guid of
+    // the header count.
+    pub fn replay_log(&mut self) -> Result<Option<ReplayResult>, VhdxError> {
+        if self.read_only {
+            return Err(VhdxError::ReadOnly);
+        }
+
+        let log_guid = self.header().log_guid;
+        if log_guid.is_nil() {
+            return Ok(None);
+        }
+        let entries: Vec<LogEntry> = self
+            .log
+            .log_sequence
+            .entries
+            .iter()
+            .filter(|entry| entry.header.log_guid == log_guid)
+            .cloned()
+            .collect();
+        // A complete entry of this log writing outside the file was not torn but crafted, the
+        // file is refused rather than replayed around it
+        for entry in self
+            .log
+            .log_entries
+            .iter()
+            .filter(|entry| entry.header.log_guid == log_guid)
+        {
+            if let Err(e @ VhdxError::InvalidLogDescriptor { .. }) = entry.validate() {
+                return Err(e);
+            }
+        }
+        let Some(head) = entries.last() else {
+            return Ok(None);
+        };
+
+        self.regenerate_write_guid()?;
+
+        let mut result = ReplayResult::default();
+        for entry in &entries {
+            entry.replay(&mut self.file, &mut result)?;
+        }
+
+        // The file has to be at least as large as what the log guarantees to be flushed
+        let flushed = head.header.flushed_file_offset;
+        let mut file_size = self.file.seek(SeekFrom::End(0))?;
+        if file_size < flushed {
+            self.file.seek(SeekFrom::Start(flushed - 1))?;
+            self.file.write_all(&[0])?;
+            file_size = flushed;
+        }
+        self.file.flush()?;
+        self.file_size = file_size;
+
+        self.retire_log()?;
+
+        result.new_file_write_guid = self.header().file_write_guid;
+        result.new_log_guid = self.header().log_guid;
+        Ok(Some(result))
+    }
+
+    // Marks the log as no longer in use once everything it records is on disk, a nil log guid
+    // tells the next open there is nothing to replay.
+    fn retire_log(&mut self) -> Result<(), VhdxError> {
+        self.log.log_sequence.entries.clear();
+        self.header_mut().log_guid = Uuid::nil();
+        self.flush()
+    }
+
+    // Writes a new log entry holding the descriptors at the head of the log and flushes it. When
+    // the log was not in use yet, the header is first updated with a new file write guid and log
+    // guid, so the entry is found and replayed should the update be interrupted.
+    pub fn append_log_entry(&mut self, descriptors: Vec<Descriptor>) -> Result<(), VhdxError> {
+        if self.read_only {
+            return Err(VhdxError::ReadOnly);
+        }
+
+        let log_length = self.header().log_length as u64;
+        let entry_length = LogEntry::length_for(&descriptors);
+        if entry_length > log_length {
+            return Err(VhdxError::LogFull(entry_length));
+        }
+
+        if self.header().log_guid.is_nil() {
+            self.log.log_sequence.entries.clear();
+            let header = self.header_mut();
+            header.file_write_guid = new_guid();
+            header.log_guid = new_guid();
+            self.flush()?;
+        }
+        let (mut head, seq_number, tail) = self.next_log_entry_position()?;
+
+        // Entries are never split at the end of the log, the entry continues at the log start
+        if head + entry_length > log_length {
+            head = 0;
+        }
+        // Entries from the tail on may not have been applied yet, so they are never overwritten
+        if !self.log.log_sequence.entries.is_empty() && head <= tail && head + entry_length > tail {
+            return Err(VhdxError::LogFull(entry_length));
+        }
+
+        let file_size = self.file_size.div_ceil(Vhdx::MB) * Vhdx::MB;
+        let mut entry = LogEntry::create(
+            seq_number,
+            tail as u32,
+            self.header().log_guid,
+            file_size,
+            file_size,
+            descriptors,
+        );
+        entry.log_offset = head;
+
+        self.file
+            .seek(SeekFrom::Start(self.header().log_offset + head))?;
+        entry.serialize(&mut self.file)?;
+        self.file.flush()?;
+
+        let sequence = &mut self.log.log_sequence;
+        if sequence.entries.is_empty() {
+            sequence.sequence_number = seq_number;
+            sequence.tail_value = tail;
+        }
+        sequence.head_value = head;
+        sequence.entries.push(entry.clone());
+        self.log.log_entries.push(entry);
+
+        Ok(())
+    }
+
+    // Replaces a single BAT entry, meant for repair tooling fixing for example a bad file offset.
+    // The BAT sector holding the entry is recorded in the log before it is written in place, the
+    // log is retired once the sector is on disk.
+    pub fn rewrite_bat_entry(
+        &mut self,
+        index: u64,
+        state: BatEntryState,
+        file_offset_mb: u64,
+    ) -> Result<(), VhdxError> {
+        if self.read_only {
+            return Err(VhdxError::ReadOnly);
+        }
+        if index >= self.bat_table.len() as u64 {
+            return Err(VhdxError::BatIndexOutOfRange(index));
+        }
+        if file_offset_mb >= 1 << 44 {
+            return Err(VhdxError::InvalidBatEntry(index));
+        }
+
+        let r = get_current_region_table(
+            &self.header.region_table_1,
+            &self.header.region_table_2,
+            true,
+        )?;
+        let bat_offset = r
+            .table_entries
+            .get(&KnowRegion::Bat)
+            .ok_or(VhdxError::MissingKnownRegion(KnowRegion::Bat.name()))?
+            .file_offset;
+
+        let entry = BatEntry::new(state, file_offset_mb as usize);
+        let entry_offset = bat_offset + index * 8;
+        let sector_offset = entry_offset / (4 * Vhdx::KB) * (4 * Vhdx::KB);
+        let mut sector = [0; 4096];
+        self.file.seek(SeekFrom::Start(sector_offset))?;
+        self.file.read_exact(&mut sector)?;
+        let within = (entry_offset - sector_offset) as usize;
+        sector[within..within + 8].copy_from_slice(&entry.to_bits().to_le_bytes());
+
+        self.append_log_entry(vec![Descriptor::data(sector_offset, &sector)])?;
+        self.file.seek(SeekFrom::Start(sector_offset))?;
+        self.file.write_all(&sector)?;
+        self.file.flush()?;
+        self.retire_log()?;
+        self.bat_table[index as usize] = entry;
+
+        Ok(())
+    }
+
+    // Restores a corrupt region table copy from the valid one. Both copies are rewritten through
+    // the log, the corrupt one first so a torn write never hits the only valid copy. Nothing is
+    // done when both are valid and there is nothing to restore from when neither is.
+    pub fn repair_region_table(&mut self) -> Result<(), VhdxError> {
+        if self.read_only {
+            return Err(VhdxError::ReadOnly);
+        }
+
+        let (r1, r2) = (&self.header.region_table_1, &self.header.region_table_2);
+        let (valid, offsets) = match (r1.validate().is_ok(), r2.validate().is_ok()) {
+            (true, true) => return Ok(()),
+            (true, false) => (r1, [256 * Vhdx::KB, 192 * Vhdx::KB]),
+            (false, true) => (r2, [192 * Vhdx::KB, 256 * Vhdx::KB]),
+            (false, false) => return Err(VhdxError::RegionTablesDegraded),
+        };
+        let mut table = Vec::new();
+        valid.serialize(&mut table)?;
+
+        let descriptors = offsets
+            .into_iter()
+            .flat_map(|offset| {
+                table
+                    .chunks_exact(4096)
+                    .enumerate()
+                    .map(move |(i, sector)| {
+                        Descriptor::data(offset + i as u64 * 4096, sector.try_into().unwrap())
+                    })
+            })
+            .collect();
+        self.append_log_entry(descriptors)?;
+
+        for offset in offsets {
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.write_all(&table)?;
+            self.file.flush()?;
+        }
+        self.retire_log()?;
+
+        self.header.region_table_1 = RegionTable::deserialize(&mut std::io::Cursor::new(&table))?;
+        self.header.region_table_2 = RegionTable::deserialize(&mut std::io::Cursor::new(&table))?;
+        Ok(())
+    }
 }
 
-fn check_sign_and_crc(header: &Header) -> Result<(), VhdxError> {
-    if header.signature != Signature::Head {
-        return Err(VhdxError::SignatureError(
-            Signature::Head,
-            header.signature.clone(),
-        ));
+impl<T> Vhdx<T>
+where
+    T: Read + Seek,
+{
+    // Parses an image from any seekable source such as a Cursor over bytes already in memory,
+    // the reader is only read from and no log is replayed
+    pub fn from_reader(reader: T) -> Result<Self, VhdxError> {
+        Vhdx::from_reader_with(reader, &VhdxOptions::default())
     }
 
-    let crc = header.crc32();
-    if header.checksum != crc {
-        return Err(VhdxError::Crc32Error(header.checksum, crc));
+    pub(crate) fn from_reader_with(mut file: T, options: &VhdxOptions) -> Result<Self, VhdxError> {
+        let file_size = file.seek(SeekFrom::End(0))?;
+        // Compact test images place everything below the first MB
+        if !options.relax_alignment && file_size < Vhdx::minimum_vhdx_size() {
+            return Err(VhdxError::FileTooSmall { size: file_size });
+        }
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = CountingReader::new(&mut file);
+        let mut metrics = ParseMetrics::default();
+
+        let start = Instant::now();
+        let header = VhdxHeader::deserialize(&mut reader)?;
+        let (current_header, h) =
+            get_current_header(&header.header_1, &header.header_2, options.verify_checksums)?;
+        // Other versions may lay out the remaining structures differently, so the version is
+        // checked before anything else is parsed
+        h.validate()?;
+
+        // A misplaced log would be read from the middle of other structures, so unlike the
+        // region table issues this is never downgraded to a warning
+        if !options.relax_alignment {
+            h.validate_alignment()?;
+        }
+
+        let r = get_current_region_table(
+            &header.region_table_1,
+            &header.region_table_2,
+            options.verify_checksums,
+        )?;
+        let mut issues = r.conformance_issues();
+        if !options.relax_alignment {
+            issues.extend(r.validate_alignment().err());
+        }
+        let mut warnings = Vec::new();
+        for issue in issues {
+            options.strictness.apply(issue, &mut warnings)?;
+        }
+
+        let meta_data_info = &r
+            .table_entries
+            .get(&KnowRegion::MetaData)
+            .ok_or(VhdxError::MissingKnownRegion("MetaData"))?;
+
+        let bat_table_info = &r
+            .table_entries
+            .get(&KnowRegion::Bat)
+            .ok_or(VhdxError::MissingKnownRegion("Bat"))?;
+        metrics.headers = start.elapsed();
+
+        let start = Instant::now();
+        let log_end = h.log_offset.saturating_add(h.log_length as u64);
+        if log_end > file_size {
+            return Err(VhdxError::LogRegionBeyondEof {
+                end: log_end,
+                file_size,
+            });
+        }
+        let mut log_entries = read_log_entries(&mut reader, h, 0)?;
+
+        // The log is circular, when the active sequence wraps around its end the newest entries
+        // are found at the start and the tail of the newest entry points to the older ones
+        let newest = log_entries
+            .iter()
+            .filter(|entry| entry.header.log_guid == h.log_guid && entry.validate().is_ok())
+            .max_by_key(|entry| entry.sequence_number());
+        let read_until = log_entries
+            .last()
+            .map_or(0, |entry| entry.log_offset + entry.entry_length() as u64);
+        if let Some(tail) = newest.map(|entry| entry.header.tail as u64) {
+            if !h.log_guid.is_nil() && tail >= read_until {
+                let mut wrapped = read_log_entries(&mut reader, h, tail)?;
+                wrapped.append(&mut log_entries);
+                log_entries = wrapped;
+            }
+        }
+        let log = Log::new(log_entries)?;
+        metrics.log = start.elapsed();
+
+        // Read MetaData
+        let start = Instant::now();
+        reader.seek(SeekFrom::Start(meta_data_info.file_offset))?;
+        let meta_data = MetaData::deserialize(&mut reader)?;
+        metrics.meta_data = start.elapsed();
+
+        // Read BAT Table
+        let start = Instant::now();
+        reader.seek(SeekFrom::Start(bat_table_info.file_offset))?;
+        let bat_table = (0..meta_data.bat_entry_count())
+            .map(|_| BatEntry::deserialize(&mut reader))
+            .collect::<Result<Vec<BatEntry>, VhdxError>>()?;
+        metrics.bat = start.elapsed();
+
+        metrics.bytes_read = reader.bytes_read;
+        metrics.reads = reader.reads;
+
+        let mut vhdx = Vhdx {
+            file,
+            file_size,
+            header,
+            log,
+            meta_data,
+            bat_table,
+            parent: None,
+            metrics: options.collect_metrics.then_some(metrics),
+            read_only: options.read_only,
+            current_header,
+            warnings,
+        };
+
+        vhdx.try_log_replay()?;
+
+        Ok(vhdx)
     }
 
-    Ok(())
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // Issues found on open that were accepted because of the strictness level
+    pub fn warnings(&self) -> &[VhdxError] {
+        &self.warnings
+    }
+
+    // Drops the reader and keeps the parsed structures, so they can be cached or sent to another
+    // thread without holding the file open
+    pub fn into_snapshot(self) -> VhdxSnapshot {
+        VhdxSnapshot {
+            file_size: self.file_size,
+            header: self.header,
+            log: self.log,
+            meta_data: self.meta_data,
+            bat_table: self.bat_table,
+            parent: self.parent.map(|parent| Box::new(parent.into_snapshot())),
+        }
+    }
+
+    // Timings and byte counts of the parse, only present when opened with collect_metrics set
+    pub fn metrics(&self) -> Option<&ParseMetrics> {
+        self.metrics.as_ref()
+    }
+
+    fn try_log_replay(&mut self) -> Result<(), VhdxError> {
+        if Uuid::is_nil(&self.header().log_guid) {
+            return Ok(());
+        }
+
+        // A file smaller than what the log guarantees to be flushed was truncated afterwards
+        let log_guid = self.header().log_guid;
+        if let Some(head) = self.log.log_sequence.entries.last() {
+            let flushed = head.header.flushed_file_offset;
+            if head.header.log_guid == log_guid && flushed > self.file_size {
+                return Err(VhdxError::FileTruncatedBelowFlushed {
+                    flushed,
+                    actual: self.file_size,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    // Returns true when every sector in the virtual range is stored in this disk, ranges touching
+    // blocks that are absent, zero or deferred to a parent are not allocated. Partially present
+    // blocks are resolved through the sector bitmap of their chunk.
+    pub fn is_range_allocated(&mut self, offset: u64, len: u64) -> Result<bool, VhdxError> {
+        let virtual_disk_size = self.meta_data.virtual_disk_size as u64;
+        let end = offset
+            .checked_add(len)
+            .filter(|end| *end <= virtual_disk_size)
+            .ok_or(VhdxError::RangeOutOfBounds(offset, len))?;
+        if len == 0 {
+            return Ok(true);
+        }
+
+        let block_size = self.meta_data.file_parameters.block_size as u64;
+        let chunk_ratio = self.meta_data.chunk_ratio;
+        for block in offset / block_size..=(end - 1) / block_size {
+            let entry = match self
+                .bat_table
+                .get(payload_bat_index(block, chunk_ratio) as usize)
+            {
+                Some(entry) => *entry,
+                None => return Ok(false),
+            };
+
+            match entry.state() {
+                BatEntryState::FullyPresent => continue,
+                BatEntryState::PartiallyPresent => {
+                    let block_start = block * block_size;
+                    let start = offset.max(block_start);
+                    let stop = end.min(block_start + block_size);
+                    if !self.sectors_present(start, stop)? {
+                        return Ok(false);
+                    }
+                }
+                _ => return Ok(false),
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Checks the sector bitmap bits covering the virtual byte range [start, stop), the range must
+    // lie within a single chunk.
+    pub(crate) fn sectors_present(&mut self, start: u64, stop: u64) -> Result<bool, VhdxError> {
+        let sector_size = self.meta_data.logical_sector_size as u64;
+        let chunk_ratio = self.meta_data.chunk_ratio;
+        let sectors_per_chunk =
+            chunk_ratio * self.meta_data.file_parameters.block_size as u64 / sector_size;
+
+        let first_sector = start / sector_size;
+        let last_sector = (stop - 1) / sector_size;
+        let chunk = first_sector / sectors_per_chunk;
+        let bitmap_offset = match self
+            .bat_table
+            .get(sector_bitmap_bat_index(chunk, chunk_ratio) as usize)
+            .and_then(BatEntry::file_offset)
+        {
+            Some(bitmap_offset) => bitmap_offset,
+            None => return Ok(false),
+        };
+
+        let first_bit = first_sector % sectors_per_chunk;
+        let last_bit = last_sector % sectors_per_chunk;
+        let mut bitmap = vec![0; (last_bit / 8 - first_bit / 8 + 1) as usize];
+        let position = self.file.stream_position()?;
+        self.file
+            .seek(SeekFrom::Start(bitmap_offset + first_bit / 8))?;
+        let result = self.file.read_exact(&mut bitmap);
+        self.file.seek(SeekFrom::Start(position))?;
+        result?;
+
+        let base = first_bit / 8 * 8;
+        let bitmap = SectorBitmap::from_bytes(bitmap);
+        Ok((first_bit..=last_bit).all(|bit| bitmap.is_present(bit - base)))
+    }
+
+    // Streams the contents of every fully present payload block together with its virtual block
+    // index, blocks without data in this file are skipped.
+    pub fn present_blocks(
+        &mut self,
+    ) -> impl Iterator<Item = Result<(u64, Vec<u8>), VhdxError>> + '_ {
+        let block_size = self.meta_data.file_parameters.block_size as u64;
+        let virtual_disk_size = self.meta_data.virtual_disk_size as u64;
+        (0..self.meta_data.payload_blocks_count).filter_map(move |block| {
+            let entry = self
+                .bat_table
+                .get(payload_bat_index(block, self.meta_data.chunk_ratio) as usize)?;
+            if entry.state() != BatEntryState::FullyPresent {
+                return None;
+            }
+            let file_offset = entry.file_offset()?;
+            let length = block_size.min(virtual_disk_size - block * block_size);
+
+            let mut buffer = vec![0; length as usize];
+            let result = self
+                .file
+                .seek(SeekFrom::Start(file_offset))
+                .and_then(|_| self.file.read_exact(&mut buffer));
+            Some(result.map(|_| (block, buffer)).map_err(VhdxError::from))
+        })
+    }
+
+    // CRC-32C over the bytes stored for a payload block, None when the block has no data in this
+    // file. Stable as long as the block is not written, which makes it usable for change detection.
+    pub fn block_crc32c(&mut self, block: u64) -> Result<Option<u32>, VhdxError> {
+        let block_size = self.meta_data.file_parameters.block_size as u64;
+        if block >= self.meta_data.payload_blocks_count {
+            return Err(VhdxError::RangeOutOfBounds(block * block_size, block_size));
+        }
+
+        let file_offset = match self
+            .bat_table
+            .get(payload_bat_index(block, self.meta_data.chunk_ratio) as usize)
+            .and_then(BatEntry::file_offset)
+        {
+            Some(file_offset) => file_offset,
+            None => return Ok(None),
+        };
+        let length = block_size.min(self.meta_data.virtual_disk_size as u64 - block * block_size);
+
+        let position = self.file.stream_position()?;
+        self.file.seek(SeekFrom::Start(file_offset))?;
+        let result = crc32c_stream(&mut self.file, length);
+        self.file.seek(SeekFrom::Start(position))?;
+
+        result.map(Some)
+    }
+
+    // Contents of a payload block as they would be after replaying the active log, the pending
+    // data and zero descriptors are overlaid on the stored bytes without writing anything. A block
+    // without data in this file reads as zeros.
+    pub fn effective_block(&mut self, block: u64) -> Result<Vec<u8>, VhdxError> {
+        let block_size = self.meta_data.file_parameters.block_size as u64;
+        if block >= self.meta_data.payload_blocks_count {
+            return Err(VhdxError::RangeOutOfBounds(block * block_size, block_size));
+        }
+
+        let length = block_size.min(self.meta_data.virtual_disk_size as u64 - block * block_size);
+        let mut buffer = vec![0; length as usize];
+        let file_offset = match self
+            .bat_table
+            .get(payload_bat_index(block, self.meta_data.chunk_ratio) as usize)
+            .and_then(BatEntry::file_offset)
+        {
+            Some(file_offset) => file_offset,
+            None => return Ok(buffer),
+        };
+
+        let position = self.file.stream_position()?;
+        self.file.seek(SeekFrom::Start(file_offset))?;
+        let result = self.file.read_exact(&mut buffer);
+        self.file.seek(SeekFrom::Start(position))?;
+        result?;
+
+        // Entries only belong to the active log when they carry the log guid of the header
+        let log_guid = self.header().log_guid;
+        if !log_guid.is_nil() {
+            self.log
+                .log_sequence
+                .entries
+                .iter()
+                .filter(|entry| entry.header.log_guid == log_guid)
+                .for_each(|entry| entry.apply_to(file_offset, &mut buffer));
+        }
+
+        Ok(buffer)
+    }
+
+    // Payload blocks whose presence or stored contents differ from another image of the same disk,
+    // compared through the CRC-32C of every block. Blocks beyond the end of the smaller disk
+    // count as absent there.
+    pub fn changed_blocks_vs<U: Read + Seek>(
+        &mut self,
+        other: &mut Vhdx<U>,
+    ) -> Result<Vec<u64>, VhdxError> {
+        let blocks = self
+            .meta_data
+            .payload_blocks_count
+            .max(other.meta_data.payload_blocks_count);
+
+        let mut changed = Vec::new();
+        for block in 0..blocks {
+            let ours = match block < self.meta_data.payload_blocks_count {
+                true => self.block_crc32c(block)?,
+                false => None,
+            };
+            let theirs = match block < other.meta_data.payload_blocks_count {
+                true => other.block_crc32c(block)?,
+                false => None,
+            };
+            if ours != theirs {
+                changed.push(block);
+            }
+        }
+        Ok(changed)
+    }
+
+    // Every parsed log entry with a descriptor writing to the given file offset, in log order
+    pub fn log_entries_touching(&self, file_offset: u64) -> Vec<&LogEntry> {
+        self.log
+            .log_entries
+            .iter()
+            .filter(|entry| entry.touches(file_offset))
+            .collect()
+    }
+
+    // Returns the raw bytes of the metadata region as declared by the region table, which allows
+    // comparing the parsed values against what is actually stored including unused space.
+    pub fn raw_metadata(&mut self) -> Result<Vec<u8>, VhdxError> {
+        self.read_region(KnowRegion::MetaData)
+    }
+
+    // Returns the raw bytes of the BAT region including the interleaved sector bitmap entries and
+    // the unused space up to the declared region length.
+    pub fn raw_bat(&mut self) -> Result<Vec<u8>, VhdxError> {
+        self.read_region(KnowRegion::Bat)
+    }
+
+    fn read_region(&mut self, region: KnowRegion) -> Result<Vec<u8>, VhdxError> {
+        let r = get_current_region_table(
+            &self.header.region_table_1,
+            &self.header.region_table_2,
+            true,
+        )?;
+        let entry = r
+            .table_entries
+            .get(&region)
+            .ok_or(VhdxError::MissingKnownRegion(region.name()))?;
+        let (offset, length) = (entry.file_offset, entry.length() as usize);
+
+        let position = self.file.stream_position()?;
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut buffer = vec![0; length];
+        let result = self.file.read_exact(&mut buffer);
+        self.file.seek(SeekFrom::Start(position))?;
+        result?;
+
+        Ok(buffer)
+    }
+
+    // Maps every payload block to the file offset holding its data (if any) and the block
+    // length, which gives a physical layout of the virtual disk within the file.
+    pub fn bat_block_map(&self) -> Vec<(u64, Option<u64>, u64)> {
+        let block_size = self.meta_data.file_parameters.block_size as u64;
+        let chunk_ratio = self.meta_data.chunk_ratio;
+        (0..self.meta_data.payload_blocks_count)
+            .map(|block| {
+                let file_offset = self
+                    .bat_table
+                    .get(payload_bat_index(block, chunk_ratio) as usize)
+                    .and_then(BatEntry::file_offset);
+                (block, file_offset, block_size)
+            })
+            .collect()
+    }
+
+    // Bytes stored after the end of the last structure the file describes, which are the header
+    // section, the regions and every block referenced from the BAT. Some tools append data to the
+    // image, it is never read but makes the file larger than its contents.
+    pub fn trailing_bytes(&mut self) -> Result<u64, VhdxError> {
+        let r = get_current_region_table(
+            &self.header.region_table_1,
+            &self.header.region_table_2,
+            true,
+        )?;
+        let regions_end = r
+            .table_entries
+            .values()
+            .map(|entry| entry.file_offset + entry.length() as u64)
+            .max()
+            .unwrap_or(0);
+
+        let block_size = self.meta_data.file_parameters.block_size as u64;
+        let chunk_ratio = self.meta_data.chunk_ratio;
+        let blocks_end = self
+            .bat_table
+            .iter()
+            .enumerate()
+            .filter_map(|(index, entry)| {
+                let length = match is_sector_bitmap_index(index as u64, chunk_ratio) {
+                    true => Vhdx::MB,
+                    false => block_size,
+                };
+                entry.file_offset().map(|offset| offset + length)
+            })
+            .max()
+            .unwrap_or(0);
+
+        let position = self.file.stream_position()?;
+        let file_size = self.file.seek(SeekFrom::End(0))?;
+        self.file.seek(SeekFrom::Start(position))?;
+
+        let end = Vhdx::MB.max(regions_end).max(blocks_end);
+        Ok(file_size.saturating_sub(end))
+    }
+
+    // Every payload block of the virtual disk in order, the sector bitmap entries interleaved
+    // in the BAT are skipped
+    pub fn blocks(&self) -> impl Iterator<Item = BlockInfo> + '_ {
+        let chunk_ratio = self.meta_data.chunk_ratio;
+        self.bat_table
+            .iter()
+            .enumerate()
+            .filter(move |(index, _)| !is_sector_bitmap_index(*index as u64, chunk_ratio))
+            .take(self.meta_data.payload_blocks_count as usize)
+            .enumerate()
+            .map(|(block, (_, entry))| BlockInfo {
+                index: block as u64,
+                state: entry.state(),
+                file_offset: entry.file_offset(),
+            })
+    }
+
+    // Capacity of the virtual disk as advertised to the guest
+    pub fn virtual_size(&self) -> u64 {
+        self.meta_data.virtual_disk_size as u64
+    }
+
+    // Space taken in the file by (partially) present payload blocks, sector bitmap blocks are not
+    // counted. For a dynamic disk this is what the disk actually consumes.
+    pub fn allocated_size(&self) -> u64 {
+        let block_size = self.meta_data.file_parameters.block_size as u64;
+        self.blocks()
+            .filter(|block| block.file_offset.is_some())
+            .count() as u64
+            * block_size
+    }
+
+    // True when no payload block holds data, as for a freshly created dynamic disk. Such a disk
+    // is empty but valid, there is nothing to back up.
+    pub fn is_empty_disk(&self) -> bool {
+        let chunk_ratio = self.meta_data.chunk_ratio;
+        (0..self.meta_data.payload_blocks_count).all(|block| {
+            self.bat_table
+                .get(payload_bat_index(block, chunk_ratio) as usize)
+                .is_none_or(|entry| {
+                    matches!(
+                        entry.state(),
+                        BatEntryState::NotPresent | BatEntryState::Zero
+                    )
+                })
+        })
+    }
+
+    // A cleanly closed file carries two identical valid copies of the region table, a file that
+    // only opens through one of them is readable but structurally degraded.
+    pub fn region_tables_both_valid(&self) -> bool {
+        let (r1, r2) = (&self.header.region_table_1, &self.header.region_table_2);
+        r1.validate().is_ok() && r2.validate().is_ok() && r1.table_entries == r2.table_entries
+    }
+
+    // Runs every structural check that does not stop the file from opening and returns all
+    // problems found, an empty list means the file is fully consistent.
+    pub fn validate_all(&self) -> Vec<VhdxError> {
+        let mut problems = Vec::new();
+
+        for header in [&self.header.header_1, &self.header.header_2] {
+            if let Err(e) = check_sign_and_crc(header, true) {
+                problems.push(e);
+            }
+        }
+
+        if !self.region_tables_both_valid() {
+            problems.push(VhdxError::RegionTablesDegraded);
+        }
+
+        if let Err(e) = self.validate_bat() {
+            problems.push(e);
+        }
+
+        problems
+    }
+
+    // Two present payload blocks sharing file space, or a block pointing past the end of the
+    // file, can only be the result of a corrupt BAT.
+    pub fn validate_bat(&self) -> Result<(), VhdxError> {
+        let file_size = self.file_size;
+        let mut present: Vec<(u64, u64, u64)> = self
+            .bat_block_map()
+            .into_iter()
+            .filter_map(|(block, offset, length)| offset.map(|offset| (block, offset, length)))
+            .collect();
+        present.sort_by_key(|(_, offset, _)| *offset);
+
+        for (block, offset, length) in &present {
+            if offset + length > file_size {
+                return Err(VhdxError::BatBlockBeyondEof(*block, *offset));
+            }
+        }
+
+        for pair in present.windows(2) {
+            let (block, offset, length) = pair[0];
+            let (next_block, next_offset, _) = pair[1];
+            if next_offset < offset + length {
+                return Err(VhdxError::BatBlockOverlap(block, next_block));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn verify_checksums(&self, report_plain_crc32: bool) -> Vec<ChecksumReport> {
+        let report = |structure: &'static str, stored: u32, value: &dyn Crc32| ChecksumReport {
+            structure,
+            stored,
+            crc32c: value.crc32(),
+            plain_crc32: report_plain_crc32.then(|| value.crc32_with(&CRC_32_ISO_HDLC)),
+        };
+
+        let mut reports = vec![
+            report(
+                "Header 1",
+                self.header.header_1.checksum,
+                &self.header.header_1,
+            ),
+            report(
+                "Header 2",
+                self.header.header_2.checksum,
+                &self.header.header_2,
+            ),
+            report(
+                "Region Table 1",
+                self.header.region_table_1.checksum,
+                &self.header.region_table_1,
+            ),
+            report(
+                "Region Table 2",
+                self.header.region_table_2.checksum,
+                &self.header.region_table_2,
+            ),
+        ];
+        reports.extend(
+            self.log
+                .log_entries
+                .iter()
+                .map(|entry| report("Log Entry", entry.header.checksum, entry)),
+        );
+        reports
+    }
+
+    fn peek_signature(&mut self) -> Result<Signature, VhdxError> {
+        let mut buffer = [0; 4];
+        self.file.read_exact(&mut buffer)?;
+        let mut peeker = peek(t_sign_u32);
+        let (_, signature) = peeker(&buffer)?;
+        self.file.seek(SeekFrom::Current(-4))?;
+        Ok(signature)
+    }
+}
+
+// Layout used for new images, the log directly follows the 1MB header section and is followed by
+// the metadata and BAT regions.
+fn write_new_image<W: Write + Seek>(
+    out: &mut W,
+    meta_data: &MetaData,
+    bat_entries: u64,
+) -> Result<(), VhdxError> {
+    let log_offset = Vhdx::MB;
+    let meta_data_offset = 2 * Vhdx::MB;
+    let bat_offset = 3 * Vhdx::MB;
+    let bat_length = (bat_entries * 8).div_ceil(Vhdx::MB).max(1) * Vhdx::MB;
+
+    let fti = FileTypeIdentifier::new(Signature::Vhdxfile, "vhdx-rs".to_string());
+    let file_write_guid = new_guid();
+    let data_write_guid = new_guid();
+    let header = |seq_number| {
+        Header::new(
+            Signature::Head,
+            0,
+            seq_number,
+            file_write_guid,
+            data_write_guid,
+            Uuid::nil(),
+            0,
+            1,
+            Vhdx::MB as u32,
+            log_offset,
+        )
+    };
+    let region_table = RegionTable::with_entries(BTreeMap::from([
+        (
+            KnowRegion::Bat,
+            RTEntry::new(RegionTable::BAT_ENTRY, bat_offset, bat_length as u32, true),
+        ),
+        (
+            KnowRegion::MetaData,
+            RTEntry::new(
+                RegionTable::META_DATA_ENTRY,
+                meta_data_offset,
+                Vhdx::MB as u32,
+                true,
+            ),
+        ),
+    ]));
+
+    out.seek(SeekFrom::Start(0))?;
+    fti.serialize(out)?;
+    out.seek(SeekFrom::Start(64 * Vhdx::KB))?;
+    header(0).serialize(out)?;
+    out.seek(SeekFrom::Start(128 * Vhdx::KB))?;
+    header(1).serialize(out)?;
+    out.seek(SeekFrom::Start(192 * Vhdx::KB))?;
+    region_table.serialize(out)?;
+    out.seek(SeekFrom::Start(256 * Vhdx::KB))?;
+    region_table.serialize(out)?;
+
+    out.seek(SeekFrom::Start(log_offset))?;
+    out.write_all(&vec![0; Vhdx::MB as usize])?;
+
+    let mut meta_data_region = Vec::new();
+    meta_data.serialize(&mut meta_data_region)?;
+    meta_data_region.resize(Vhdx::MB as usize, 0);
+    out.seek(SeekFrom::Start(meta_data_offset))?;
+    out.write_all(&meta_data_region)?;
+
+    out.seek(SeekFrom::Start(bat_offset))?;
+    out.write_all(&vec![0; bat_length as usize])?;
+    out.flush()?;
+    Ok(())
+}
+
+// Version 4 GUID without pulling in a random number generator, RandomState is seeded with random
+// keys by the standard library.
+pub(crate) fn new_guid() -> Uuid {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut bytes = [0; 16];
+    for chunk in bytes.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or_default(),
+        );
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    Builder::from_random_bytes(bytes).into_uuid()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumReport {
+    pub structure: &'static str,
+    pub stored: u32,
+    pub crc32c: u32,
+
+    // Plain CRC-32 (ISO-HDLC) over the same bytes, only computed when requested. Useful when a
+    // foreign tool wrote the structure with the wrong algorithm.
+    pub plain_crc32: Option<u32>,
+}
+
+impl ChecksumReport {
+    pub fn is_valid(&self) -> bool {
+        self.stored == self.crc32c
+    }
+}
+
+// Summary of a disk, see Vhdx::info
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VhdxInfo {
+    pub disk_type: DiskType,
+    pub virtual_disk_id: Uuid,
+    pub virtual_disk_size: u64,
+    pub block_size: u64,
+    pub logical_sector_size: u64,
+    pub physical_sector_size: u64,
+    pub file_size: u64,
+}
+
+// Parsed structures of an image without its reader, see Vhdx::into_snapshot. Only inspection is
+// possible, reading sectors needs the original file.
+#[derive(Debug)]
+pub struct VhdxSnapshot {
+    pub file_size: u64,
+    pub header: VhdxHeader,
+    pub log: Log,
+    pub meta_data: MetaData,
+    pub bat_table: Vec<BatEntry>,
+    pub parent: Option<Box<VhdxSnapshot>>,
+}
+
+impl VhdxSnapshot {
+    pub fn disk_type(&self) -> DiskType {
+        self.meta_data.file_parameters.disk_type()
+    }
+}
+
+// Relative locator paths are resolved against the directory of the child, windows separators are
+// translated so images created on windows can be opened elsewhere.
+fn resolve_parent_path(child_path: &Path, locator: &ParentLocator) -> Result<PathBuf, VhdxError> {
+    let to_path = |p: &str| PathBuf::from(p.replace('\\', std::path::MAIN_SEPARATOR_STR));
+
+    if let Some(relative) = locator.relative_path() {
+        let dir = child_path.parent().unwrap_or_else(|| Path::new(""));
+        let candidate = dir.join(to_path(relative));
+        if candidate.exists() || locator.absolute_win32_path().is_none() {
+            return Ok(candidate);
+        }
+    }
+
+    locator
+        .absolute_win32_path()
+        .map(to_path)
+        .ok_or(VhdxError::MissingParentLocator)
+}
+
+fn open_file(path: &Path, write: bool) -> std::io::Result<File> {
+    File::options().read(true).write(write).open(path)
+}
+
+// Reads the entries stored back to back from the given offset in the log, up to the first sector
+// that does not start an entry or the end of the log
+fn read_log_entries<R: Read + Seek>(
+    reader: &mut R,
+    header: &Header,
+    start: u64,
+) -> Result<Vec<LogEntry>, VhdxError> {
+    let log_length = header.log_length as u64;
+    let mut log_entries = Vec::new();
+    let mut offset = start;
+
+    while offset < log_length {
+        // peeking to see if there are any more logs, an unused log has none at all
+        reader.seek(SeekFrom::Start(header.log_offset + offset))?;
+        let mut buffer = [0; 4];
+        reader.read_exact(&mut buffer)?;
+        let mut peeker = peek(t_sign_u32);
+        let (_, signature) = peeker(&buffer)?;
+        if signature != Signature::Loge {
+            break;
+        }
+        // The entry length is checked against the log before anything is read up to it
+        reader.seek(SeekFrom::Start(header.log_offset + offset))?;
+        let entry_length = LogHeader::deserialize(reader)?.entry_length as u64;
+        if entry_length > log_length - offset {
+            return Err(VhdxError::LogFull(entry_length));
+        }
+        reader.seek(SeekFrom::Start(header.log_offset + offset))?;
+        let mut log_entry = LogEntry::deserialize(reader)?;
+        log_entry.log_offset = offset;
+        log_entries.push(log_entry);
+        offset += entry_length;
+    }
+    Ok(log_entries)
+}
+
+// The region tables are not tied to a header, the first table is used unless it is corrupt.
+fn get_current_region_table<'a>(
+    r1: &'a RegionTable,
+    r2: &'a RegionTable,
+    verify_checksums: bool,
+) -> Result<&'a RegionTable, VhdxError> {
+    // The checksum is the last check, so a mismatch means the table is otherwise valid
+    let check = |r: &RegionTable| match r.validate() {
+        Err(VhdxError::Crc32Error(_, _)) if !verify_checksums => Ok(()),
+        result => result,
+    };
+    match check(r1) {
+        Ok(()) => Ok(r1),
+        Err(_) => check(r2).map(|_| r2),
+    }
+}
+
+fn get_current_header<'a>(
+    h1: &'a Header,
+    h2: &'a Header,
+    verify_checksums: bool,
+) -> Result<(u32, &'a Header), VhdxError> {
+    let r1 = check_sign_and_crc(h1, verify_checksums);
+    let r2 = check_sign_and_crc(h2, verify_checksums);
+
+    let current = if r1.is_err() && r2.is_err() {
+        // TODO: Better error handling
+        return Err(VhdxError::VhdxHeaderError);
+    } else if r2.is_err() || (r1.is_ok() && h1.sequence_number() > h2.sequence_number()) {
+        (1, h1)
+    } else {
+        (2, h2)
+    };
+    Ok(current)
+}
+
+fn check_sign_and_crc(header: &Header, verify_checksum: bool) -> Result<(), VhdxError> {
+    if header.signature != Signature::Head {
+        return Err(VhdxError::SignatureError(
+            Signature::Head,
+            header.signature.clone(),
+        ));
+    }
+
+    let crc = header.crc32();
+    if verify_checksum && header.checksum != crc {
+        return Err(VhdxError::Crc32Error(header.checksum, crc));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_vhdx;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    #[test]
+    fn verify_checksums_reports_plain_crc32_on_request() {
+        let vhdx = Vhdx::new(&"test.vhdx").unwrap();
+
+        let reports = vhdx.verify_checksums(true);
+        assert_eq!(6, reports.len());
+        reports.iter().for_each(|r| {
+            assert!(r.is_valid());
+            assert_ne!(Some(r.crc32c), r.plain_crc32);
+        });
+
+        assert!(vhdx
+            .verify_checksums(false)
+            .iter()
+            .all(|r| r.plain_crc32.is_none()));
+    }
+
+    #[test]
+    fn bat_block_map_lists_payload_blocks() {
+        let mut vhdx = Vhdx::new(&"test.vhdx").unwrap();
+
+        let map = vhdx.bat_block_map();
+        assert_eq!(
+            vec![
+                (0, Some(4 * Vhdx::MB), 2 * Vhdx::MB),
+                (1, Some(6 * Vhdx::MB), 2 * Vhdx::MB)
+            ],
+            map
+        );
+
+        // Two blocks claiming the same offset shows up in the map
+        vhdx.bat_table[1].file_offset_mb = 4;
+        let map = vhdx.bat_block_map();
+        assert_eq!(map[0].1, map[1].1);
+    }
+
+    #[test]
+    fn trailing_bytes_after_last_block() {
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image.clone())).unwrap();
+        assert_eq!(0, vhdx.trailing_bytes().unwrap());
+
+        image.extend_from_slice(&[0xEE; 4096]);
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        assert_eq!(4096, vhdx.trailing_bytes().unwrap());
+    }
+
+    #[test]
+    fn validate_bat_detects_overlapping_blocks() {
+        let mut vhdx = Vhdx::new(&"test.vhdx").unwrap();
+        assert!(vhdx.validate_bat().is_ok());
+
+        vhdx.bat_table[1].file_offset_mb = 5;
+        assert!(matches!(
+            vhdx.validate_bat(),
+            Err(VhdxError::BatBlockOverlap(0, 1))
+        ));
+    }
+
+    #[test]
+    fn validate_bat_detects_blocks_beyond_eof() {
+        let mut vhdx = Vhdx::new(&"test.vhdx").unwrap();
+
+        vhdx.bat_table[1].file_offset_mb = 7;
+        assert!(matches!(
+            vhdx.validate_bat(),
+            Err(VhdxError::BatBlockBeyondEof(1, offset)) if offset == 7 * Vhdx::MB
+        ));
+    }
+
+    #[test]
+    fn new_differencing_references_parent() {
+        let parent = Vhdx::new(&"test.vhdx").unwrap();
+        let mut child = Cursor::new(Vec::new());
+        Vhdx::new_differencing(&mut child, &parent, Path::new("test.vhdx")).unwrap();
+
+        let child = Vhdx::from_reader(child).unwrap();
+        assert!(child.meta_data.file_parameters.has_parent);
+        assert_eq!(DiskType::Differencing, child.disk_type());
+        assert!(!child.meta_data.file_parameters.leave_block_allocated);
+        assert_eq!(
+            parent.meta_data.virtual_disk_size,
+            child.meta_data.virtual_disk_size
+        );
+
+        let locator = child.meta_data.parent_locator.as_ref().unwrap();
+        assert_eq!(ParentLocator::VHDX_PARENT_LOCATOR, locator.locator_type);
+        assert_eq!(
+            Some(parent.header.header_2.data_write_guid),
+            locator.parent_linkage()
+        );
+        assert_eq!(Some("test.vhdx"), locator.relative_path());
+    }
+
+    #[test]
+    fn create_dynamic_writes_empty_disk() {
+        let path = temp_path("create-dynamic");
+        let created = Vhdx::create_dynamic(
+            &path,
+            10 * Vhdx::MB,
+            2 * Vhdx::MB as u32,
+            SectorSize::Sector512,
+        );
+        let reopened = Vhdx::open_readonly(&path);
+        std::fs::remove_file(&path).unwrap();
+        let created = created.unwrap();
+        let mut reopened = reopened.unwrap();
+
+        assert_eq!(DiskType::Dynamic, reopened.disk_type());
+        assert_eq!(1, reopened.header().sequence_number());
+        assert_eq!(
+            created.header().file_write_guid,
+            reopened.header().file_write_guid
+        );
+        assert!(reopened.header().log_guid.is_nil());
+        assert_eq!(10 * Vhdx::MB, reopened.virtual_size());
+        assert_eq!(2 * Vhdx::MB as usize, reopened.block_size());
+        assert_eq!(5, reopened.blocks().count());
+        assert!(reopened
+            .blocks()
+            .all(|block| block.state == BatEntryState::NotPresent));
+        assert!(reopened
+            .verify_checksums(false)
+            .iter()
+            .all(|r| r.is_valid()));
+
+        let mut raw = Vec::new();
+        reopened.export_raw(&mut raw).unwrap();
+        assert!(raw.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn create_dynamic_rejects_invalid_geometry() {
+        let path = temp_path("create-dynamic-invalid");
+        let block_size = Vhdx::create_dynamic(
+            &path,
+            4 * Vhdx::MB,
+            3 * Vhdx::MB as u32,
+            SectorSize::Sector512,
+        );
+        let unaligned = Vhdx::create_dynamic(
+            &path,
+            4 * Vhdx::MB + 512,
+            Vhdx::MB as u32,
+            SectorSize::Sector4096,
+        );
+        let empty = Vhdx::create_dynamic(&path, 0, Vhdx::MB as u32, SectorSize::Sector512);
+        let _ = std::fs::remove_file(&path);
+
+        assert!(matches!(block_size, Err(VhdxError::BlockSizeError(_))));
+        assert!(matches!(
+            unaligned,
+            Err(VhdxError::VirtualSizeNotSectorAligned(_, 4096))
+        ));
+        assert!(matches!(empty, Err(VhdxError::NotAllowedToBeZero(_))));
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("vhdx-rs-{}-{}.vhdx", std::process::id(), name))
+    }
+
+    fn write_child(name: &str, parent: &Vhdx) -> std::path::PathBuf {
+        let parent_path = std::fs::canonicalize("test.vhdx").unwrap();
+        let child_path = temp_path(name);
+        let out = File::create(&child_path).unwrap();
+        Vhdx::new_differencing(out, parent, &parent_path).unwrap();
+        child_path
+    }
+
+    #[test]
+    fn truncated_file_too_small() {
+        let path = temp_path("too-small");
+        std::fs::write(&path, [0; 100]).unwrap();
+        let result = Vhdx::new(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(VhdxError::FileTooSmall { size: 100 })));
+    }
+
+    #[test]
+    fn scan_dir_reports_each_file() {
+        let dir = temp_path("scan");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::copy("test.vhdx", dir.join("good.vhdx")).unwrap();
+        std::fs::write(dir.join("corrupt.vhdx"), vec![0; 512 * 1024]).unwrap();
+        std::fs::write(dir.join("notes.txt"), b"not a disk").unwrap();
+
+        let results = Vhdx::scan_dir(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(2, results.len());
+        assert_eq!(dir.join("corrupt.vhdx"), results[0].0);
+        assert!(results[0].1.is_err());
+
+        assert_eq!(dir.join("good.vhdx"), results[1].0);
+        let info = results[1].1.as_ref().unwrap();
+        assert_eq!(DiskType::Fixed, info.disk_type);
+        assert_eq!(4 * Vhdx::MB, info.virtual_disk_size);
+        assert_eq!(2 * Vhdx::MB, info.block_size);
+        assert_eq!(512, info.logical_sector_size);
+        assert_eq!(8 * Vhdx::MB, info.file_size);
+    }
+
+    #[test]
+    fn snapshot_outlives_reader() {
+        let snapshot = Vhdx::new(&"test.vhdx").unwrap().into_snapshot();
+
+        // The snapshot holds no file handle and can move to another thread
+        let snapshot = std::thread::spawn(move || snapshot).join().unwrap();
+        assert_eq!(4 * Vhdx::MB as usize, snapshot.meta_data.virtual_disk_size);
+        assert_eq!(
+            2 * Vhdx::MB as usize,
+            snapshot.meta_data.file_parameters.block_size
+        );
+        assert_eq!(DiskType::Fixed, snapshot.disk_type());
+        assert_eq!(2, snapshot.bat_table.len());
+        assert!(snapshot.parent.is_none());
+    }
+
+    #[test]
+    fn region_of_fixture() {
+        let vhdx = Vhdx::new(&"test.vhdx").unwrap();
+        let bat = vhdx.region(KnowRegion::Bat).unwrap();
+        assert_eq!(3 * Vhdx::MB, bat.file_offset);
+        assert_eq!(Vhdx::MB, bat.length() as u64);
+        assert_eq!(
+            2 * Vhdx::MB,
+            vhdx.region(KnowRegion::MetaData).unwrap().file_offset
+        );
+        assert!(vhdx.region(KnowRegion::Unknown(Uuid::nil())).is_none());
+    }
+
+    #[test]
+    fn raw_metadata_covers_region() {
+        let mut vhdx = Vhdx::new(&"test.vhdx").unwrap();
+        let position = vhdx.file.stream_position().unwrap();
+        let length = vhdx.header.region_table_2.table_entries[&KnowRegion::MetaData].length();
+
+        let raw = vhdx.raw_metadata().unwrap();
+        assert_eq!(length as usize, raw.len());
+        assert_eq!(b"metadata", &raw[..8]);
+        assert_eq!(position, vhdx.file.stream_position().unwrap());
+    }
+
+    // Rewrites the file offsets of every region in both region tables and fixes up their CRCs
+    fn patch_region_offsets(image: &mut [u8], relocate: impl Fn(u64) -> u64) {
+        const REGION_TABLES: [usize; 2] = [192 * 1024, 256 * 1024];
+
+        for table in REGION_TABLES {
+            for entry in 0..2 {
+                let offset = table + 16 + entry * 32 + 16;
+                let file_offset = u64::from_le_bytes(image[offset..offset + 8].try_into().unwrap());
+                image[offset..offset + 8].copy_from_slice(&relocate(file_offset).to_le_bytes());
+            }
+            image[table + 4..table + 8].fill(0);
+            let crc =
+                crc::Crc::<u32>::new(&crc::CRC_32_ISCSI).checksum(&image[table..table + 64 * 1024]);
+            image[table + 4..table + 8].copy_from_slice(&crc.to_le_bytes());
+        }
+    }
+
+    // Swaps the metadata and BAT regions of the fixture so metadata ends up behind the BAT
+    fn fixture_with_metadata_after_bat() -> Vec<u8> {
+        const MB: usize = Vhdx::MB as usize;
+        let mut image = std::fs::read("test.vhdx").unwrap();
+
+        let (low, high) = image[2 * MB..4 * MB].split_at_mut(MB);
+        low.swap_with_slice(high);
+        patch_region_offsets(&mut image, |file_offset| {
+            if file_offset == 2 * Vhdx::MB {
+                3 * Vhdx::MB
+            } else {
+                2 * Vhdx::MB
+            }
+        });
+
+        image
+    }
+
+    // Adds an unknown optional region and sets the reserved field in both region tables
+    fn fixture_with_nonconformant_region_table() -> Vec<u8> {
+        const REGION_TABLES: [usize; 2] = [192 * 1024, 256 * 1024];
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        for table in REGION_TABLES {
+            image[table + 8..table + 12].copy_from_slice(&3_u32.to_le_bytes());
+            image[table + 12..table + 16].copy_from_slice(&1_u32.to_le_bytes());
+            let entry = table + 16 + 2 * 32;
+            image[entry..entry + 16].copy_from_slice(&[0xAB; 16]);
+            image[entry + 16..entry + 24].copy_from_slice(&(7 * Vhdx::MB).to_le_bytes());
+            image[entry + 24..entry + 28].copy_from_slice(&(Vhdx::MB as u32).to_le_bytes());
+            image[entry + 28..entry + 32].fill(0);
+
+            image[table + 4..table + 8].fill(0);
+            let crc =
+                crc::Crc::<u32>::new(&crc::CRC_32_ISCSI).checksum(&image[table..table + 65536]);
+            image[table + 4..table + 8].copy_from_slice(&crc.to_le_bytes());
+        }
+        image
+    }
+
+    #[test]
+    fn strictness_levels() {
+        let image = fixture_with_nonconformant_region_table();
+        let open = |strictness| {
+            let options = VhdxOptions {
+                strictness,
+                ..Default::default()
+            };
+            Vhdx::from_reader_with(Cursor::new(image.clone()), &options)
+        };
+
+        assert!(open(Strictness::Lenient).unwrap().warnings().is_empty());
+
+        let vhdx = open(Strictness::Normal).unwrap();
+        assert_eq!(2, vhdx.warnings().len());
+        assert!(matches!(
+            vhdx.warnings()[0],
+            VhdxError::UnknownRTEntryFound(_)
+        ));
+        assert!(matches!(
+            vhdx.warnings()[1],
+            VhdxError::ReservedNotZero("Region Table")
+        ));
+        assert!(vhdx.region_tables_both_valid());
+
+        assert!(matches!(
+            open(Strictness::Strict),
+            Err(VhdxError::UnknownRTEntryFound(_))
+        ));
+
+        // The unmodified fixture conforms at every level
+        let vhdx = Vhdx::new(&"test.vhdx").unwrap();
+        assert!(vhdx.warnings().is_empty());
+    }
+
+    #[test]
+    fn blocks_skip_sector_bitmap_entries() {
+        let mut vhdx = Vhdx::new(&"test.vhdx").unwrap();
+        let blocks: Vec<BlockInfo> = vhdx.blocks().collect();
+        assert_eq!(
+            vec![
+                BlockInfo {
+                    index: 0,
+                    state: BatEntryState::FullyPresent,
+                    file_offset: Some(4 * Vhdx::MB),
+                },
+                BlockInfo {
+                    index: 1,
+                    state: BatEntryState::FullyPresent,
+                    file_offset: Some(6 * Vhdx::MB),
+                },
+            ],
+            blocks
+        );
+
+        // With a chunk ratio of 1 every other entry is a sector bitmap entry
+        vhdx.meta_data.chunk_ratio = 1;
+        vhdx.bat_table = vec![
+            BatEntry::new(BatEntryState::FullyPresent, 4),
+            BatEntry::new(BatEntryState::FullyPresent, 10),
+            BatEntry::new(BatEntryState::NotPresent, 0),
+            BatEntry::new(BatEntryState::NotPresent, 0),
+        ];
+        let blocks: Vec<BlockInfo> = vhdx.blocks().collect();
+        assert_eq!(2, blocks.len());
+        assert_eq!(Some(4 * Vhdx::MB), blocks[0].file_offset);
+        assert_eq!(1, blocks[1].index);
+        assert_eq!(BatEntryState::NotPresent, blocks[1].state);
+        assert_eq!(None, blocks[1].file_offset);
+    }
+
+    #[test]
+    fn allocated_and_virtual_size() {
+        let mut vhdx = Vhdx::new(&"test.vhdx").unwrap();
+        assert_eq!(4 * Vhdx::MB, vhdx.virtual_size());
+        assert_eq!(4 * Vhdx::MB, vhdx.allocated_size());
+
+        // The present sector bitmap entry is not part of the allocated size
+        vhdx.meta_data.chunk_ratio = 1;
+        vhdx.bat_table = vec![
+            BatEntry::new(BatEntryState::FullyPresent, 4),
+            BatEntry::new(BatEntryState::FullyPresent, 10),
+            BatEntry::new(BatEntryState::Zero, 0),
+            BatEntry::new(BatEntryState::NotPresent, 0),
+        ];
+        assert_eq!(2 * Vhdx::MB, vhdx.allocated_size());
+    }
+
+    #[test]
+    fn region_in_header_area() {
+        const HALF_MB: u64 = 512 * 1024;
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        let meta_data = 2 * Vhdx::MB as usize;
+        image.copy_within(meta_data..meta_data + 128 * 1024, HALF_MB as usize);
+        patch_region_offsets(&mut image, |file_offset| {
+            if file_offset == 2 * Vhdx::MB {
+                HALF_MB
+            } else {
+                file_offset
+            }
+        });
+
+        let options = VhdxOptions {
+            relax_alignment: true,
+            ..Default::default()
+        };
+        let vhdx = Vhdx::from_reader_with(Cursor::new(image), &options).unwrap();
+        assert!(matches!(
+            vhdx.validate_against_spec_offsets(),
+            Err(VhdxError::RegionInHeaderArea("MetaData", HALF_MB))
+        ));
+
+        let vhdx = Vhdx::new(&"test.vhdx").unwrap();
+        vhdx.validate_against_spec_offsets().unwrap();
+    }
+
+    #[test]
+    fn region_below_first_mb_needs_relaxed_alignment() {
+        // Moves the metadata table and its items into the unused space behind the region tables
+        const COMPACT: u64 = 384 * 1024;
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        let meta_data = 2 * Vhdx::MB as usize;
+        image.copy_within(meta_data..meta_data + 128 * 1024, COMPACT as usize);
+        patch_region_offsets(&mut image, |file_offset| {
+            if file_offset == 2 * Vhdx::MB {
+                COMPACT
+            } else {
+                file_offset
+            }
+        });
+
+        let strict = VhdxOptions {
+            strictness: Strictness::Strict,
+            ..Default::default()
+        };
+        assert!(matches!(
+            Vhdx::from_reader_with(Cursor::new(image.clone()), &strict),
+            Err(VhdxError::RegionOffsetError(COMPACT))
+        ));
+        let normal = Vhdx::from_reader(Cursor::new(image.clone())).unwrap();
+        assert!(matches!(
+            normal.warnings(),
+            [VhdxError::RegionOffsetError(COMPACT)]
+        ));
+
+        let options = VhdxOptions {
+            relax_alignment: true,
+            ..Default::default()
+        };
+        let vhdx = Vhdx::from_reader_with(Cursor::new(image), &options).unwrap();
+        let original = Vhdx::new(&"test.vhdx").unwrap();
+        assert_eq!(
+            original.meta_data.virtual_disk_id,
+            vhdx.meta_data.virtual_disk_id
+        );
+        assert_eq!(original.bat_block_map(), vhdx.bat_block_map());
+    }
+
+    #[test]
+    fn metadata_region_after_bat_region() {
+        let image = fixture_with_metadata_after_bat();
+        let mut swapped = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        let mut original = Vhdx::new(&"test.vhdx").unwrap();
+
+        let r = &swapped.header.region_table_2.table_entries;
+        assert!(r[&KnowRegion::MetaData].file_offset > r[&KnowRegion::Bat].file_offset);
+        assert_eq!(original.bat_block_map(), swapped.bat_block_map());
+        assert_eq!(
+            original.meta_data.virtual_disk_id,
+            swapped.meta_data.virtual_disk_id
+        );
+        assert_eq!(
+            original.raw_metadata().unwrap(),
+            swapped.raw_metadata().unwrap()
+        );
+    }
+
+    fn set_bat_entry(image: &mut [u8], index: usize, state: u64, file_offset_mb: u64) {
+        let offset = 3 * Vhdx::MB as usize + index * 8;
+        let value = state | (file_offset_mb << 20);
+        image[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn empty_disk_has_no_allocated_blocks() {
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        assert!(!parse_vhdx(&image).unwrap().is_empty_disk());
+
+        set_bat_entry(&mut image, 0, BatEntryState::Zero as u64, 0);
+        assert!(!parse_vhdx(&image).unwrap().is_empty_disk());
+
+        set_bat_entry(&mut image, 1, BatEntryState::NotPresent as u64, 0);
+        assert!(parse_vhdx(&image).unwrap().is_empty_disk());
+    }
+
+    #[test]
+    fn range_spanning_present_and_absent_block() {
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        set_bat_entry(&mut image, 1, BatEntryState::NotPresent as u64, 0);
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+
+        assert!(vhdx.is_range_allocated(0, 2 * Vhdx::MB).unwrap());
+        assert!(!vhdx.is_range_allocated(Vhdx::MB, 2 * Vhdx::MB).unwrap());
+        assert!(vhdx.is_range_allocated(4 * Vhdx::MB, 1).is_err());
+    }
+
+    #[test]
+    fn range_in_partially_present_block_uses_sector_bitmap() {
+        let parent = Vhdx::new(&"test.vhdx").unwrap();
+        let mut child = Cursor::new(Vec::new());
+        Vhdx::new_differencing(&mut child, &parent, Path::new("test.vhdx")).unwrap();
+
+        // Sector bitmap at 4MB with the first 8 sectors present
+        let mut image = child.into_inner();
+        image.resize(5 * Vhdx::MB as usize, 0);
+        image[4 * Vhdx::MB as usize] = 0xFF;
+        let chunk_ratio = parent.meta_data.chunk_ratio as usize;
+        set_bat_entry(&mut image, 0, BatEntryState::PartiallyPresent as u64, 5);
+        set_bat_entry(
+            &mut image,
+            chunk_ratio,
+            BatEntryState::FullyPresent as u64,
+            4,
+        );
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+
+        assert!(vhdx.is_range_allocated(0, 4096).unwrap());
+        assert!(!vhdx.is_range_allocated(0, 4097).unwrap());
+        assert!(!vhdx.is_range_allocated(2 * Vhdx::MB, 512).unwrap());
+    }
+
+    #[test]
+    fn metrics_only_collected_on_request() {
+        assert!(Vhdx::new(&"test.vhdx").unwrap().metrics().is_none());
+
+        let options = VhdxOptions {
+            collect_metrics: true,
+            ..Default::default()
+        };
+        let vhdx = Vhdx::open_with(&"test.vhdx", &options).unwrap();
+        let metrics = vhdx.metrics().unwrap();
+
+        // Both log entries and the BAT are read completely, but never more than the whole file
+        let minimum = 4096 + 73728 + 2 * 8;
+        assert!(metrics.bytes_read >= minimum);
+        assert!(metrics.bytes_read <= vhdx.file_size);
+        assert!(metrics.reads > 0);
+        assert_eq!(
+            metrics.headers + metrics.log + metrics.meta_data + metrics.bat,
+            metrics.total()
+        );
+    }
+
+    #[test]
+    fn log_entries_touching_bat() {
+        let vhdx = Vhdx::new(&"test.vhdx").unwrap();
+        assert_eq!(2, vhdx.log.log_entries.len());
+
+        // Only the second entry carries descriptors, one of them updated the BAT
+        let touching = vhdx.log_entries_touching(3 * Vhdx::MB + 8);
+        assert_eq!(1, touching.len());
+        assert_eq!(
+            vhdx.log.log_entries[1].header.seq_number,
+            touching[0].header.seq_number
+        );
+        assert!(vhdx.log_entries_touching(5 * Vhdx::MB).is_empty());
+    }
+
+    #[test]
+    fn zeroed_second_header_selects_first() {
+        const HEADER_2: usize = 128 * 1024;
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        image[HEADER_2..HEADER_2 + 4096].fill(0);
+
+        let vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        let h2 = &vhdx.header.header_2;
+        assert_eq!(Signature::Unknown(vec![0; 4]), h2.signature);
+        // A zeroed checksum field must not match the CRC computed over the zeroed header
+        assert_eq!(0, h2.checksum);
+        assert_ne!(0, h2.crc32());
+
+        let (header_no, _) = get_current_header(&vhdx.header.header_1, h2, true).unwrap();
+        assert_eq!(1, header_no);
+    }
+
+    #[test]
+    fn raw_bat_covers_region() {
+        let mut vhdx = Vhdx::new(&"test.vhdx").unwrap();
+        let position = vhdx.file.stream_position().unwrap();
+        let length = vhdx.header.region_table_2.table_entries[&KnowRegion::Bat].length();
+
+        let raw = vhdx.raw_bat().unwrap();
+        assert_eq!(length as usize, raw.len());
+        let first = u64::from_le_bytes(raw[..8].try_into().unwrap());
+        assert_eq!(BatEntryState::FullyPresent as u64, first & 0b111);
+        assert_eq!(4, first >> 20);
+        assert_eq!(position, vhdx.file.stream_position().unwrap());
+    }
+
+    #[test]
+    fn effective_block_overlays_active_log() {
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        let payload = 4 * Vhdx::MB as usize..6 * Vhdx::MB as usize;
+        image[payload.clone()].fill(0xAA);
+        let stored = image[payload].to_vec();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        assert_eq!(stored, vhdx.effective_block(0).unwrap());
+
+        // Block 0 is stored at 4MB, zero its first sector and overwrite the second one
+        let sector: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+        let descriptors = vec![
+            Descriptor::zero(4 * Vhdx::MB, 4096),
+            Descriptor::data(4 * Vhdx::MB + 4096, sector.as_slice().try_into().unwrap()),
+        ];
+        vhdx.append_log_entry(descriptors).unwrap();
+
+        let block = vhdx.effective_block(0).unwrap();
+        assert_eq!(stored.len(), block.len());
+        assert!(block[..4096].iter().all(|b| *b == 0));
+        assert_eq!(sector.as_slice(), &block[4096..8192]);
+        assert_eq!(&stored[8192..], &block[8192..]);
+
+        // Nothing was written to the payload itself
+        let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI).checksum(&stored);
+        assert_eq!(Some(crc), vhdx.block_crc32c(0).unwrap());
+    }
+
+    #[test]
+    fn changed_blocks_between_versions() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut original = Vhdx::from_reader(Cursor::new(image.clone())).unwrap();
+        let mut same = Vhdx::from_reader(Cursor::new(image.clone())).unwrap();
+        assert!(original.changed_blocks_vs(&mut same).unwrap().is_empty());
+
+        let mut modified = image;
+        modified[6 * Vhdx::MB as usize + 1234] = 0x5A;
+        let mut modified = Vhdx::from_reader(Cursor::new(modified)).unwrap();
+        assert_eq!(vec![1], original.changed_blocks_vs(&mut modified).unwrap());
+
+        modified.bat_table[0] = BatEntry::new(BatEntryState::NotPresent, 0);
+        assert_eq!(
+            vec![0, 1],
+            original.changed_blocks_vs(&mut modified).unwrap()
+        );
+    }
+
+    #[test]
+    fn block_crc32c_is_stable() {
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        set_bat_entry(&mut image, 1, BatEntryState::NotPresent as u64, 0);
+        image[4 * Vhdx::MB as usize] = 0xAB;
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image.clone())).unwrap();
+
+        let first = vhdx.block_crc32c(0).unwrap().unwrap();
+        vhdx.raw_metadata().unwrap();
+        assert_eq!(Some(first), vhdx.block_crc32c(0).unwrap());
+
+        let block = &image[4 * Vhdx::MB as usize..6 * Vhdx::MB as usize];
+        let expected = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI).checksum(block);
+        assert_eq!(expected, first);
+        assert_eq!(None, vhdx.block_crc32c(1).unwrap());
+        assert!(vhdx.block_crc32c(2).is_err());
+    }
+
+    #[test]
+    fn corrupt_second_region_table_is_degraded() {
+        const REGION_TABLE_2: usize = 256 * 1024;
+        let vhdx = Vhdx::new(&"test.vhdx").unwrap();
+        assert!(vhdx.region_tables_both_valid());
+        assert!(vhdx.validate_all().is_empty());
+
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        image[REGION_TABLE_2 + 4] ^= 0xFF;
+        let vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        assert!(!vhdx.region_tables_both_valid());
+
+        let problems = vhdx.validate_all();
+        assert_eq!(1, problems.len());
+        assert!(matches!(problems[0], VhdxError::RegionTablesDegraded));
+    }
+
+    #[test]
+    fn repair_region_table_restores_corrupt_copy() {
+        const REGION_TABLE_2: usize = 256 * 1024;
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        image[REGION_TABLE_2 + 4] ^= 0xFF;
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        assert!(!vhdx.region_tables_both_valid());
+
+        vhdx.repair_region_table().unwrap();
+        assert!(vhdx.region_tables_both_valid());
+
+        let reread = parse_vhdx(vhdx.file.get_ref()).unwrap();
+        assert!(reread.region_tables_both_valid());
+        assert!(reread.validate_all().is_empty());
+
+        // The log was referenced by header 1 during the rewrite and retired by header 2
+        let log_guid = reread.log.log_entries[0].header.log_guid;
+        assert!(!log_guid.is_nil());
+        assert_eq!(log_guid, reread.header.header_1.log_guid);
+        assert_eq!(2, reread.active_header_index());
+        assert!(reread.header().log_guid.is_nil());
+    }
+
+    #[test]
+    fn open_readonly_leaves_pending_log() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        vhdx.append_log_entry(vec![Descriptor::zero(6 * Vhdx::MB, 4096)])
+            .unwrap();
+        let pending = vhdx.file.into_inner();
+
+        let path = temp_path("read-only");
+        std::fs::write(&path, &pending).unwrap();
+        let mut vhdx = Vhdx::open_readonly(&path).unwrap();
+        let log_guid = vhdx.header().log_guid;
+        let replay = vhdx.replay_log();
+        let flush = vhdx.flush();
+        drop(vhdx);
+        let after = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(!log_guid.is_nil());
+        assert!(matches!(replay, Err(VhdxError::ReadOnly)));
+        assert!(matches!(flush, Err(VhdxError::ReadOnly)));
+        assert_eq!(pending, after);
+    }
+
+    #[test]
+    fn from_reader_over_owned_bytes() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut in_memory = Vhdx::from_reader(Cursor::new(image.clone())).unwrap();
+        let from_file = Vhdx::new(&"test.vhdx").unwrap();
+        assert_eq!(
+            from_file.info().virtual_disk_id,
+            in_memory.info().virtual_disk_id
+        );
+
+        let sector = in_memory.read_sector(0).unwrap();
+        assert_eq!(
+            &image[4 * Vhdx::MB as usize..4 * Vhdx::MB as usize + 512],
+            &sector[..]
+        );
+    }
+
+    #[test]
+    fn geometry_accessors() {
+        let vhdx = Vhdx::new(&"test.vhdx").unwrap();
+        let info = vhdx.info();
+        assert_eq!(info.virtual_disk_id, vhdx.disk_id());
+        assert!(!vhdx.disk_id().is_nil());
+        assert_eq!(512, vhdx.logical_sector_size());
+        assert_eq!(
+            info.physical_sector_size,
+            vhdx.physical_sector_size() as u64
+        );
+        assert_eq!(2 * Vhdx::MB as usize, vhdx.block_size());
+    }
+
+    #[test]
+    fn checksums_skipped_on_request() {
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        for offset in [64, 128, 192, 256] {
+            let checksum = offset * Vhdx::KB as usize + 4;
+            image[checksum] ^= 0xFF;
+        }
+        assert!(matches!(
+            parse_vhdx(&image),
+            Err(VhdxError::VhdxHeaderError)
+        ));
+
+        let options = VhdxOptions {
+            verify_checksums: false,
+            ..Default::default()
+        };
+        let vhdx = Vhdx::from_reader_with(Cursor::new(&image), &options).unwrap();
+        assert_eq!(5, vhdx.header().sequence_number());
+        assert_eq!(4 * Vhdx::MB, vhdx.virtual_size());
+
+        // Signatures are still checked
+        image[128 * Vhdx::KB as usize] = b'x';
+        image[64 * Vhdx::KB as usize] = b'x';
+        assert!(matches!(
+            Vhdx::from_reader_with(Cursor::new(&image), &options),
+            Err(VhdxError::VhdxHeaderError)
+        ));
+    }
+
+    #[test]
+    fn pending_log_kept_without_replay() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        vhdx.append_log_entry(vec![Descriptor::zero(6 * Vhdx::MB, 4096)])
+            .unwrap();
+        let pending = vhdx.file.into_inner();
+
+        let path = temp_path("no-replay");
+        std::fs::write(&path, &pending).unwrap();
+        let options = VhdxOptions {
+            replay_log: false,
+            ..Default::default()
+        };
+        let vhdx = Vhdx::open_with(&path, &options);
+        let after = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let vhdx = vhdx.unwrap();
+        assert!(!vhdx.is_read_only());
+        assert!(!vhdx.header().log_guid.is_nil());
+        assert_eq!(pending, after);
+    }
+
+    #[test]
+    fn open_auto_falls_back_to_read_only() {
+        let vhdx = Vhdx::open_auto(&"test.vhdx").unwrap();
+        assert!(!vhdx.is_read_only());
+
+        let vhdx = Vhdx::open_auto_with(Path::new("test.vhdx"), |path, write| {
+            if write {
+                Err(std::io::ErrorKind::PermissionDenied.into())
+            } else {
+                open_file(path, false)
+            }
+        })
+        .unwrap();
+        assert!(vhdx.is_read_only());
+
+        let result = Vhdx::open_auto_with(Path::new("test.vhdx"), |_, _| {
+            Err(std::io::ErrorKind::NotFound.into())
+        });
+        assert!(matches!(result, Err(VhdxError::IoError(_))));
+    }
+
+    #[test]
+    fn header_returns_current_header() {
+        let vhdx = Vhdx::new(&"test.vhdx").unwrap();
+        assert_eq!(5, vhdx.header().sequence_number());
+        assert_eq!(
+            vhdx.header.header_2.sequence_number(),
+            vhdx.header().sequence_number()
+        );
+        assert_eq!(vhdx.header.header_2.log_offset, vhdx.header().log_offset);
+
+        // With the second header zeroed the first one takes over
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        image[128 * 1024..132 * 1024].fill(0);
+        let vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        assert_eq!(4, vhdx.header().sequence_number());
+        assert_eq!(1, vhdx.active_header_index());
+    }
+
+    #[test]
+    fn creator_of_fixture() {
+        let vhdx = Vhdx::new(&"test.vhdx").unwrap();
+        assert_eq!("Microsoft Windows 10.0.19045.0", vhdx.creator());
+    }
+
+    #[test]
+    fn active_header_and_sequence_numbers() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        assert_eq!(2, vhdx.active_header_index());
+        assert_eq!((4, 5), vhdx.header_sequence_numbers());
+
+        // A header update goes to the other slot
+        vhdx.flush().unwrap();
+        assert_eq!(1, vhdx.active_header_index());
+        assert_eq!((6, 5), vhdx.header_sequence_numbers());
+    }
+
+    #[test]
+    fn log_walk_steps_over_multi_sector_entries() {
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        let log_guid = new_guid();
+        let sectors = [[0x11_u8; 4096], [0x22_u8; 4096], [0x33_u8; 4096]];
+        let descriptors = sectors
+            .iter()
+            .enumerate()
+            .map(|(i, sector)| Descriptor::data(4 * Vhdx::MB + i as u64 * 4096, sector))
+            .collect();
+        let first = LogEntry::create(10, 0, log_guid, 8 * Vhdx::MB, 8 * Vhdx::MB, descriptors);
+        let second = LogEntry::create(11, 0, log_guid, 8 * Vhdx::MB, 8 * Vhdx::MB, Vec::new());
+
+        let mut log = Vec::new();
+        first.serialize(&mut log).unwrap();
+        second.serialize(&mut log).unwrap();
+        log.resize(log.len() + 4096, 0);
+        let log_offset = Vhdx::MB as usize;
+        image[log_offset..log_offset + log.len()].copy_from_slice(&log);
+
+        let vhdx = parse_vhdx(&image).unwrap();
+        let entries = &vhdx.log.log_entries;
+        assert_eq!(2, entries.len());
+        assert_eq!(4 * 4096, entries[0].entry_length());
+        assert!((0..3).all(|i| entries[0].touches(4 * Vhdx::MB + i * 4096)));
+        assert_eq!(11, entries[1].sequence_number());
+    }
+
+    #[test]
+    fn log_walk_follows_tail_around_end_of_log() {
+        let log_guid = new_guid();
+        let mut image = fixture_with_header_field(48, &log_guid.to_bytes_le());
+        let log_offset = Vhdx::MB as usize;
+        let wrapped_offset = Vhdx::MB as usize - 2 * 4096;
+
+        // The older entry sits right before the end of the log, the newer one wrapped to its start
+        let older = LogEntry::create(
+            10,
+            wrapped_offset as u32,
+            log_guid,
+            8 * Vhdx::MB,
+            8 * Vhdx::MB,
+            vec![Descriptor::zero(4 * Vhdx::MB, 4096)],
+        );
+        let newer = LogEntry::create(
+            11,
+            wrapped_offset as u32,
+            log_guid,
+            8 * Vhdx::MB,
+            8 * Vhdx::MB,
+            Vec::new(),
+        );
+        let mut bytes = Vec::new();
+        older.serialize(&mut bytes).unwrap();
+        let start = log_offset + wrapped_offset;
+        image[start..start + bytes.len()].copy_from_slice(&bytes);
+        bytes.clear();
+        newer.serialize(&mut bytes).unwrap();
+        image[log_offset..log_offset + bytes.len()].copy_from_slice(&bytes);
+
+        let vhdx = parse_vhdx(&image).unwrap();
+        let entries = &vhdx.log.log_entries;
+        assert_eq!(10, entries[0].sequence_number());
+        assert_eq!(wrapped_offset as u64, entries[0].log_offset);
+        assert_eq!(11, entries[1].sequence_number());
+        assert_eq!(0, entries[1].log_offset);
+
+        let sequence = Vhdx::try_get_log_sequence(entries).unwrap();
+        let seqs: Vec<u64> = sequence
+            .entries
+            .iter()
+            .map(|entry| entry.sequence_number())
+            .collect();
+        assert_eq!(vec![10, 11], seqs);
+        assert_eq!(wrapped_offset as u64, sequence.tail_value);
+        assert_eq!(0, sequence.head_value);
+    }
+
+    #[test]
+    fn log_writing_outside_file_not_replayed() {
+        let log_guid = new_guid();
+        // Zeroes the whole file from the start, headers included
+        let entry = LogEntry::create(
+            10,
+            0,
+            log_guid,
+            8 * Vhdx::MB,
+            8 * Vhdx::MB,
+            vec![Descriptor::zero(0, u64::MAX - 4095)],
+        );
+        let mut bytes = Vec::new();
+        entry.serialize(&mut bytes).unwrap();
+        let log_offset = Vhdx::MB as usize;
+        let with_entry = |mut image: Vec<u8>| {
+            image[log_offset..log_offset + bytes.len()].copy_from_slice(&bytes);
+            image
+        };
+
+        let image = with_entry(fixture_with_header_field(48, &log_guid.to_bytes_le()));
+        let path = temp_path("crafted-log");
+        std::fs::write(&path, &image).unwrap();
+        let vhdx = Vhdx::new(&path);
+        let read_only = Vhdx::open_readonly(&path);
+        let after = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            vhdx,
+            Err(VhdxError::InvalidLogDescriptor { file_offset: 0, .. })
+        ));
+        assert!(read_only.is_ok());
+        assert_eq!(image, after);
+
+        // Without a log guid in the header the entry is stale and ignored
+        let stale = with_entry(std::fs::read("test.vhdx").unwrap());
+        let vhdx = parse_vhdx(&stale).unwrap();
+        assert!(vhdx.log.log_sequence.is_empty());
+    }
+
+    #[test]
+    fn padded_log_entry_checksum_agrees() {
+        let entry = LogEntry::create(
+            10,
+            0,
+            Uuid::nil(),
+            8 * Vhdx::MB,
+            8 * Vhdx::MB,
+            vec![Descriptor::zero(4 * Vhdx::MB, 4096)],
+        );
+        let mut bytes = Vec::new();
+        entry.serialize(&mut bytes).unwrap();
+        bytes.resize(2 * 4096, 0x5A);
+        bytes[8..12].copy_from_slice(&(2 * 4096_u32).to_le_bytes());
+        bytes[4..8].fill(0);
+        let checksum = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI).checksum(&bytes);
+        bytes[4..8].copy_from_slice(&checksum.to_le_bytes());
+
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        let log_offset = Vhdx::MB as usize;
+        image[log_offset..log_offset + bytes.len()].copy_from_slice(&bytes);
+        let vhdx = parse_vhdx(&image).unwrap();
+        assert!(vhdx.log.log_entries[0].validate().is_ok());
+
+        let report = vhdx
+            .verify_checksums(false)
+            .into_iter()
+            .find(|report| report.structure == "Log Entry")
+            .unwrap();
+        assert_eq!(checksum, report.stored);
+        assert_eq!(report.stored, report.crc32c);
+    }
+
+    #[test]
+    fn log_entry_longer_than_log_rejected() {
+        // The second entry of the fixture starts 4KB into the log
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        let length = Vhdx::MB as usize + 4096 + 8;
+        image[length..length + 4].copy_from_slice(&0xFFFF_F000_u32.to_le_bytes());
+        assert!(matches!(
+            parse_vhdx(&image),
+            Err(VhdxError::LogFull(0xFFFF_F000))
+        ));
+
+        // Fits the file but not the remainder of the log
+        image[length..length + 4].copy_from_slice(&(Vhdx::MB as u32).to_le_bytes());
+        assert!(matches!(parse_vhdx(&image), Err(VhdxError::LogFull(_))));
+    }
+
+    #[test]
+    fn multi_sector_log_entry_checksum() {
+        let vhdx = Vhdx::new(&"test.vhdx").unwrap();
+        let entry = &vhdx.log.log_entries[1];
+        assert_eq!(18 * 4096, entry.entry_length());
+        assert_eq!(entry.header.checksum, entry.crc32());
+    }
+
+    #[test]
+    fn append_log_entry_reads_back_valid() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        let last_seq_number = vhdx.log.log_entries[1].sequence_number();
+
+        let sectors = [[0xAB_u8; 4096], [0xCD_u8; 4096]];
+        let descriptors = vec![
+            Descriptor::data(4 * Vhdx::MB, &sectors[0]),
+            Descriptor::data(4 * Vhdx::MB + 4096, &sectors[1]),
+        ];
+        let file_write_guid = vhdx.header().file_write_guid;
+        vhdx.append_log_entry(descriptors).unwrap();
+        let log_guid = vhdx.header().log_guid;
+        assert!(!log_guid.is_nil());
+
+        // The stale entries of the fixture are overwritten from the start of the log
+        let reread = parse_vhdx(vhdx.file.get_ref()).unwrap();
+        assert_eq!(log_guid, reread.header().log_guid);
+        assert_ne!(file_write_guid, reread.header().file_write_guid);
+        let entry = &reread.log.log_entries[0];
+        assert_eq!(last_seq_number + 1, entry.sequence_number());
+        assert_eq!(3 * 4096, entry.entry_length());
+        assert_eq!(log_guid, entry.header.log_guid);
+        assert_eq!(entry.header.checksum, entry.crc32());
+        assert!(entry.touches(4 * Vhdx::MB + 4096));
+    }
+
+    #[test]
+    fn log_wrap_never_overwrites_tail() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        let sector = [0x5A_u8; 4096];
+        let descriptors = || {
+            (0..150)
+                .map(|i| Descriptor::data(4 * Vhdx::MB + i * 4096, &sector))
+                .collect::<Vec<_>>()
+        };
+
+        // The second entry does not fit behind the first one and the log start holds the tail
+        vhdx.append_log_entry(descriptors()).unwrap();
+        let entry_length = LogEntry::length_for(&descriptors());
+        assert!(matches!(
+            vhdx.append_log_entry(descriptors()),
+            Err(VhdxError::LogFull(length)) if length == entry_length
+        ));
+        assert_eq!(1, vhdx.log.log_sequence.entries.len());
+    }
+
+    #[test]
+    fn log_head_offset_follows_last_entry() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        // The fixture log isn't in use (nil log guid), so writing starts at the log start
+        assert_eq!(vhdx.header().log_offset, vhdx.log_head_offset().unwrap());
+
+        vhdx.append_log_entry(vec![Descriptor::zero(4 * Vhdx::MB, 4096)])
+            .unwrap();
+        assert_eq!(
+            vhdx.header().log_offset + 4096,
+            vhdx.log_head_offset().unwrap()
+        );
+    }
+
+    #[test]
+    fn validate_log_guids_finds_stale_entry() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        assert!(vhdx.validate_log_guids().is_ok());
+
+        vhdx.append_log_entry(vec![Descriptor::zero(4 * Vhdx::MB, 4096)])
+            .unwrap();
+        vhdx.append_log_entry(vec![Descriptor::zero(6 * Vhdx::MB, 4096)])
+            .unwrap();
+        assert!(vhdx.validate_log_guids().is_ok());
+
+        let expected = vhdx.header().log_guid;
+        let stale = new_guid();
+        vhdx.log.log_sequence.entries[0].header.log_guid = stale;
+        assert!(matches!(
+            vhdx.validate_log_guids(),
+            Err(VhdxError::LogGuidMismatch { expected: e, found: f }) if e == expected && f == stale
+        ));
+    }
+
+    #[test]
+    fn rewrite_bat_entry_reads_back() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        vhdx.rewrite_bat_entry(1, BatEntryState::PartiallyPresent, 7)
+            .unwrap();
+        assert_eq!(Some(7 * Vhdx::MB), vhdx.bat_table[1].file_offset());
+
+        let reread = parse_vhdx(vhdx.file.get_ref()).unwrap();
+        assert_eq!(BatEntryState::FullyPresent, reread.bat_table[0].state());
+        assert_eq!(BatEntryState::PartiallyPresent, reread.bat_table[1].state());
+        assert_eq!(Some(7 * Vhdx::MB), reread.bat_table[1].file_offset());
+        assert_eq!(1, reread.log_entries_touching(3 * Vhdx::MB + 8).len());
+
+        assert!(matches!(
+            vhdx.rewrite_bat_entry(2, BatEntryState::FullyPresent, 7),
+            Err(VhdxError::BatIndexOutOfRange(2))
+        ));
+        assert!(matches!(
+            vhdx.rewrite_bat_entry(0, BatEntryState::FullyPresent, 1 << 44),
+            Err(VhdxError::InvalidBatEntry(0))
+        ));
+    }
+
+    #[test]
+    fn rewrite_bat_entry_logs_through_header() {
+        let path = temp_path("rewrite-bat");
+        std::fs::copy("test.vhdx", &path).unwrap();
+        let mut vhdx = Vhdx::new(&path).unwrap();
+        let file_write_guid = vhdx.header().file_write_guid;
+        let rewrite = vhdx.rewrite_bat_entry(1, BatEntryState::PartiallyPresent, 7);
+        drop(vhdx);
+        let reopened = Vhdx::open_readonly(&path);
+        std::fs::remove_file(&path).unwrap();
+        rewrite.unwrap();
+        let reopened = reopened.unwrap();
+
+        // Header 1 referenced the log while the sector was written, header 2 retired it again
+        let log_guid = reopened.log.log_entries[0].header.log_guid;
+        assert!(!log_guid.is_nil());
+        assert_eq!(log_guid, reopened.header.header_1.log_guid);
+        assert_eq!(2, reopened.active_header_index());
+        assert_eq!(7, reopened.header().sequence_number());
+        assert!(reopened.header().log_guid.is_nil());
+        assert_ne!(file_write_guid, reopened.header().file_write_guid);
+        assert_eq!(Some(7 * Vhdx::MB), reopened.bat_table[1].file_offset());
+    }
+
+    #[test]
+    fn log_summary_counts_stale_entry() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        // The log guid is nil so none of the fixture entries is valid
+        let summary = vhdx.log_summary();
+        assert_eq!(
+            (2, 0, 2),
+            (
+                summary.entries,
+                summary.valid_entries,
+                summary.invalid_entries
+            )
+        );
+
+        // Overwrites the first fixture entry, the second one stays behind as a stale entry
+        vhdx.append_log_entry(vec![Descriptor::zero(4 * Vhdx::MB, 4096)])
+            .unwrap();
+        let seq_number = vhdx.log.log_sequence.sequence_number;
+
+        let reread = parse_vhdx(vhdx.file.get_ref()).unwrap();
+        let summary = reread.log_summary();
+        assert_eq!(
+            LogSummary {
+                log_guid: vhdx.header().log_guid,
+                entries: 2,
+                active_sequence_number: seq_number,
+                head_offset: 0,
+                tail_offset: 0,
+                valid_entries: 1,
+                invalid_entries: 1,
+                descriptor_counts: vec![1, 17],
+            },
+            summary
+        );
+    }
+
+    #[test]
+    fn replay_writes_pending_log() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image.clone())).unwrap();
+        let sector = [0x5A_u8; 4096];
+        vhdx.append_log_entry(vec![
+            Descriptor::data(4 * Vhdx::MB + 4096, &sector),
+            Descriptor::zero(6 * Vhdx::MB, 8192),
+        ])
+        .unwrap();
+        // The entry is never applied, as if the update was interrupted
+        let pending = vhdx.file.into_inner();
+        assert_eq!(
+            &image[4 * Vhdx::MB as usize..],
+            &pending[4 * Vhdx::MB as usize..]
+        );
+
+        // Nothing is replayed when opened read-only
+        let options = VhdxOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let vhdx = Vhdx::from_file_with(Cursor::new(pending.clone()), &options).unwrap();
+        assert!(!vhdx.header().log_guid.is_nil());
+        assert_eq!(pending, vhdx.file.into_inner());
+
+        let vhdx = Vhdx::from_file_with(Cursor::new(pending), &VhdxOptions::default()).unwrap();
+        assert!(vhdx.header().log_guid.is_nil());
+        let replayed = vhdx.file.into_inner();
+        let payload = 4 * Vhdx::MB as usize;
+        assert_eq!(&sector[..], &replayed[payload + 4096..payload + 8192]);
+        assert_eq!(
+            &image[payload..payload + 4096],
+            &replayed[payload..payload + 4096]
+        );
+
+        let reread = parse_vhdx(&replayed).unwrap();
+        assert!(reread.header().log_guid.is_nil());
+    }
+
+    #[test]
+    fn replay_reports_result() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        let file_write_guid = vhdx.header().file_write_guid;
+        let sector = [0x5A_u8; 4096];
+        vhdx.append_log_entry(vec![
+            Descriptor::data(4 * Vhdx::MB, &sector),
+            Descriptor::data(4 * Vhdx::MB + 4096, &sector),
+            Descriptor::zero(6 * Vhdx::MB, 8192),
+        ])
+        .unwrap();
+
+        let result = vhdx.replay_log().unwrap().unwrap();
+        assert_eq!(3, result.blocks_written);
+        assert_eq!(4096 + 4096 + 8192, result.bytes_written);
+        assert_ne!(file_write_guid, result.new_file_write_guid);
+        assert_eq!(vhdx.header().file_write_guid, result.new_file_write_guid);
+        assert!(result.new_log_guid.is_nil());
+
+        // The log is no longer in use
+        assert!(vhdx.replay_log().unwrap().is_none());
+    }
+
+    #[test]
+    fn regenerate_write_guid_updates_header() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        let previous = vhdx.header().clone();
+
+        vhdx.regenerate_write_guid().unwrap();
+        let reread = parse_vhdx(vhdx.file.get_ref()).unwrap();
+        assert_eq!(1, reread.active_header_index());
+        assert_eq!(6, reread.header().sequence_number());
+        assert_ne!(previous.file_write_guid, reread.header().file_write_guid);
+        assert_eq!(
+            vhdx.header().file_write_guid,
+            reread.header().file_write_guid
+        );
+        assert_eq!(previous.data_write_guid, reread.header().data_write_guid);
+    }
+
+    #[test]
+    fn regenerate_write_guid_refused_read_only() {
+        let mut vhdx = Vhdx::open_readonly(&"test.vhdx").unwrap();
+        let file_write_guid = vhdx.header().file_write_guid;
+        assert!(matches!(
+            vhdx.regenerate_write_guid(),
+            Err(VhdxError::ReadOnly)
+        ));
+        assert_eq!(file_write_guid, vhdx.header().file_write_guid);
+    }
+
+    #[test]
+    fn flush_writes_non_current_slot() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        assert_eq!(5, vhdx.header().sequence_number());
+
+        // The first entry persists the header referencing the log
+        vhdx.append_log_entry(vec![Descriptor::zero(4 * Vhdx::MB, 4096)])
+            .unwrap();
+        assert_eq!(1, vhdx.current_header);
+
+        let reread = parse_vhdx(vhdx.file.get_ref()).unwrap();
+        assert_eq!(1, reread.current_header);
+        assert_eq!(6, reread.header().sequence_number());
+        assert_eq!(vhdx.header().log_guid, reread.header().log_guid);
+        // The previously current header is left untouched
+        assert_eq!(5, reread.header.header_2.sequence_number());
+
+        vhdx.flush().unwrap();
+        let reread = parse_vhdx(vhdx.file.get_ref()).unwrap();
+        assert_eq!(2, reread.current_header);
+        assert_eq!(7, reread.header().sequence_number());
+    }
+
+    #[test]
+    fn truncated_below_flushed_file_offset() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+        vhdx.append_log_entry(vec![Descriptor::zero(4 * Vhdx::MB, 4096)])
+            .unwrap();
+        assert!(parse_vhdx(vhdx.file.get_ref()).is_ok());
+
+        let truncated = &vhdx.file.get_ref()[..7 * Vhdx::MB as usize];
+        match parse_vhdx(truncated) {
+            Err(VhdxError::FileTruncatedBelowFlushed { flushed, actual }) => {
+                assert_eq!(8 * Vhdx::MB, flushed);
+                assert_eq!(7 * Vhdx::MB, actual);
+            }
+            other => panic!("expected truncation error, got: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn present_blocks_skip_holes() {
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        set_bat_entry(&mut image, 0, BatEntryState::Zero as u64, 0);
+        image[6 * Vhdx::MB as usize] = 0x42;
+        let mut vhdx = Vhdx::from_reader(Cursor::new(image)).unwrap();
+
+        let blocks: Vec<(u64, Vec<u8>)> = vhdx.present_blocks().map(Result::unwrap).collect();
+        assert_eq!(1, blocks.len());
+        let (index, bytes) = &blocks[0];
+        assert_eq!(1, *index);
+        assert_eq!(2 * Vhdx::MB as usize, bytes.len());
+        assert_eq!(0x42, bytes[0]);
+    }
+
+    // Patches a field of the current (second) header of the fixture and fixes up its checksum
+    fn fixture_with_header_field(offset: usize, bytes: &[u8]) -> Vec<u8> {
+        fixture_with_header_fields(&[(offset, bytes)])
+    }
+
+    fn fixture_with_header_fields(fields: &[(usize, &[u8])]) -> Vec<u8> {
+        const HEADER_2: usize = 128 * 1024;
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        let header = &mut image[HEADER_2..HEADER_2 + 4096];
+        for (offset, bytes) in fields {
+            header[*offset..*offset + bytes.len()].copy_from_slice(bytes);
+        }
+        header[4..8].fill(0);
+        let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI).checksum(header);
+        header[4..8].copy_from_slice(&crc.to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn change_token_follows_data_write_guid() {
+        let image = std::fs::read("test.vhdx").unwrap();
+        let vhdx = parse_vhdx(&image).unwrap();
+        assert_eq!(vhdx.header().data_write_guid, vhdx.change_token());
+
+        let image = fixture_with_header_field(32, new_guid().as_bytes());
+        let changed = parse_vhdx(&image).unwrap();
+        assert_ne!(vhdx.change_token(), changed.change_token());
+    }
+
+    #[test]
+    fn unsupported_versions_rejected_early() {
+        let image = fixture_with_header_field(66, &2_u16.to_le_bytes());
+        assert!(matches!(
+            parse_vhdx(&image),
+            Err(VhdxError::VersionError(2))
+        ));
+
+        let log_guid = new_guid();
+        let image = fixture_with_header_fields(&[
+            (48, log_guid.to_bytes_le().as_slice()),
+            (64, &1_u16.to_le_bytes()),
+        ]);
+        assert!(matches!(
+            parse_vhdx(&image),
+            Err(VhdxError::LogVersionError(1))
+        ));
+    }
+
+    #[test]
+    fn version_of_stale_header_ignored() {
+        // Header 1 is the older of the two, a bad version there does not affect the open
+        const HEADER_1: usize = 64 * 1024;
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        let header = &mut image[HEADER_1..HEADER_1 + 4096];
+        header[66..68].copy_from_slice(&2_u16.to_le_bytes());
+        header[4..8].fill(0);
+        let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI).checksum(header);
+        header[4..8].copy_from_slice(&crc.to_le_bytes());
+
+        let vhdx = parse_vhdx(&image).unwrap();
+        assert_eq!(2, vhdx.current_header);
+        assert!(matches!(
+            vhdx.header.header_1.validate(),
+            Err(VhdxError::VersionError(2))
+        ));
+        assert!(vhdx.header().validate().is_ok());
+    }
+
+    #[test]
+    fn log_region_beyond_eof_rejected() {
+        let file_size = std::fs::metadata("test.vhdx").unwrap().len();
+        let image = fixture_with_header_field(72, &file_size.to_le_bytes());
+        assert!(matches!(
+            parse_vhdx(&image),
+            Err(VhdxError::LogRegionBeyondEof { end, file_size: size })
+                if end == file_size + Vhdx::MB && size == file_size
+        ));
+    }
+
+    #[test]
+    fn misaligned_log_rejected() {
+        let length = (Vhdx::MB + 4 * Vhdx::KB) as u32;
+        let image = fixture_with_header_field(68, &length.to_le_bytes());
+        assert!(matches!(
+            parse_vhdx(&image),
+            Err(VhdxError::LogLengthError(l)) if l == length
+        ));
+
+        let offset = Vhdx::MB + 4 * Vhdx::KB;
+        let image = fixture_with_header_field(72, &offset.to_le_bytes());
+        let lenient = VhdxOptions {
+            strictness: Strictness::Lenient,
+            ..Default::default()
+        };
+        assert!(matches!(
+            Vhdx::from_reader_with(Cursor::new(image), &lenient),
+            Err(VhdxError::LogOffsetError(o)) if o == offset
+        ));
+    }
+
+    #[test]
+    fn unknown_log_version_ignored_without_log() {
+        let image = fixture_with_header_field(64, &1_u16.to_le_bytes());
+        let mut vhdx = Vhdx::from_file_with(Cursor::new(image), &VhdxOptions::default()).unwrap();
+        assert_eq!(1, vhdx.header().log_version);
+        assert!(vhdx.replay_log().unwrap().is_none());
+    }
+
+    #[test]
+    fn open_chain_links_parent() {
+        let parent = Vhdx::new(&"test.vhdx").unwrap();
+        let child_path = write_child("chain-ok", &parent);
+
+        let child = Vhdx::open_chain(&child_path);
+        std::fs::remove_file(&child_path).unwrap();
+
+        let child = child.unwrap();
+        let linked = child.parent.as_ref().unwrap();
+        assert_eq!(
+            parent.header.header_2.data_write_guid,
+            linked.header.header_2.data_write_guid
+        );
+        assert!(linked.parent.is_none());
+    }
+
+    #[test]
+    fn open_chain_reads_through_both_levels() {
+        const MB: usize = Vhdx::MB as usize;
+        let parent_path = temp_path("chain-read-parent");
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        image[4 * MB..8 * MB]
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b = (i / 512 + i % 251) as u8);
+        std::fs::write(&parent_path, &image).unwrap();
+        let parent = Vhdx::new(&parent_path).unwrap();
+
+        let child_path = temp_path("chain-read-child");
+        let out = File::create(&child_path).unwrap();
+        Vhdx::new_differencing(out, &parent, &parent_path).unwrap();
+
+        // Give the child its own copy of the second block, the first stays with the parent
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&child_path)
+            .unwrap();
+        let block_offset = file.metadata().unwrap().len();
+        file.write_all(&vec![0xC5; 2 * MB]).unwrap();
+        drop(file);
+        let mut child = Vhdx::new(&child_path).unwrap();
+        child
+            .rewrite_bat_entry(
+                payload_bat_index(1, 2048),
+                BatEntryState::FullyPresent,
+                block_offset / Vhdx::MB,
+            )
+            .unwrap();
+        drop(child);
+
+        let child = Vhdx::open_chain(&child_path);
+        let mut contents = vec![0; 4 * MB];
+        let read = child.and_then(|mut child| child.read_at(0, &mut contents));
+        std::fs::remove_file(&child_path).unwrap();
+        std::fs::remove_file(&parent_path).unwrap();
+
+        assert_eq!(4 * MB, read.unwrap());
+        assert_eq!(&image[4 * MB..6 * MB], &contents[..2 * MB]);
+        assert!(contents[2 * MB..].iter().all(|b| *b == 0xC5));
+    }
+
+    #[test]
+    fn open_chain_rejects_mismatched_linkage() {
+        let mut parent = Vhdx::new(&"test.vhdx").unwrap();
+        let actual = parent.header.header_2.data_write_guid;
+        let stale = Uuid::from_u128(0x1234);
+        parent.header.header_2.data_write_guid = stale;
+        parent.header.header_2.checksum = parent.header.header_2.crc32();
+        let child_path = write_child("chain-mismatch", &parent);
+
+        let result = Vhdx::open_chain(&child_path);
+        std::fs::remove_file(&child_path).unwrap();
+
+        match result {
+            Err(VhdxError::ParentLinkageMismatch { expected, found }) => {
+                assert_eq!(stale, expected);
+                assert_eq!(actual, found);
+            }
+            other => panic!(
+                "expected ParentLinkageMismatch, got: {:?}",
+                other.map(|_| ())
+            ),
+        }
+    }
 }
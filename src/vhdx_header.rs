@@ -1,6 +1,5 @@
 use std::collections::BTreeMap;
-use std::io::{Read, Seek, SeekFrom};
-use std::iter;
+use std::io::{Read, Seek, SeekFrom, Write};
 
 use crc::{Crc, CRC_32_ISCSI};
 use nom::combinator::map;
@@ -14,7 +13,7 @@ use crate::parse_utils::{
     t_bool_u32, t_creator, t_guid, t_sign_u32, t_sign_u64, t_u16, t_u32, t_u64,
 };
 use crate::vhdx::Vhdx;
-use crate::{Crc32, DeSerialise, Signature, Validation};
+use crate::{Crc32, DeSerialise, Serialise, Signature, Validation};
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -26,7 +25,7 @@ pub struct VhdxHeader {
     pub region_table_2: RegionTable,
 }
 impl VhdxHeader {
-    fn new(
+    pub(crate) fn new(
         fti: FileTypeIdentifier,
         header_1: Header,
         header_2: Header,
@@ -41,6 +40,36 @@ impl VhdxHeader {
             region_table_2,
         }
     }
+
+    pub fn fti(&self) -> &FileTypeIdentifier {
+        &self.fti
+    }
+
+    // Deserialising seeks straight to the fixed offsets, so a file whose
+    // structures were shifted only shows up as an obscure parse failure.
+    // Checks that every structure's signature sits at its spec offset.
+    pub fn validate_layout<T>(reader: &mut T) -> Result<(), VhdxError>
+    where
+        T: Read + Seek,
+    {
+        let layout: [(&'static str, u64, &[u8]); 5] = [
+            ("File type identifier", 0, FileTypeIdentifier::SIGN),
+            ("Header 1", 64 * Vhdx::KB, Header::SIGN),
+            ("Header 2", 128 * Vhdx::KB, Header::SIGN),
+            ("Region table 1", 192 * Vhdx::KB, RegionTable::SIGN),
+            ("Region table 2", 256 * Vhdx::KB, RegionTable::SIGN),
+        ];
+
+        for (name, offset, sign) in layout {
+            let mut buffer = vec![0; sign.len()];
+            reader.seek(SeekFrom::Start(offset))?;
+            reader.read_exact(&mut buffer)?;
+            if buffer != sign {
+                return Err(VhdxError::StructureMisplaced(name, offset));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<T> DeSerialise<T> for VhdxHeader {
@@ -70,14 +99,33 @@ impl<T> DeSerialise<T> for VhdxHeader {
 pub struct FileTypeIdentifier {
     signature: Signature,
     creator: String,
+    // Whether anything was stored in the reserved space after the creator
+    extra_data: bool,
 }
 
 impl FileTypeIdentifier {
     pub const SIGN: &'static [u8] = &[0x76, 0x68, 0x64, 0x78, 0x66, 0x69, 0x6C, 0x65];
     const SIZE: usize = 65536;
+    // The 8 byte signature followed by the 512 byte creator
+    const USED_SIZE: usize = 520;
+
+    pub(crate) fn new(signature: Signature, creator: String) -> FileTypeIdentifier {
+        Self {
+            signature,
+            creator,
+            extra_data: false,
+        }
+    }
 
-    fn new(signature: Signature, creator: String) -> FileTypeIdentifier {
-        Self { signature, creator }
+    // Name of the application that created the file, for information only
+    pub fn creator(&self) -> &str {
+        &self.creator
+    }
+
+    // The remainder of the 64KB structure is reserved, but some tools stash data there which is
+    // useful to fingerprint the tool that created or touched the file.
+    pub fn has_extra_fti_data(&self) -> bool {
+        self.extra_data
     }
 }
 
@@ -91,13 +139,30 @@ impl<T> DeSerialise<T> for FileTypeIdentifier {
         let mut buffer = [0; FileTypeIdentifier::SIZE];
         reader.read_exact(&mut buffer)?;
 
-        let (_, fti) = map(tuple((t_sign_u64, t_creator)), |(signature, creator)| {
+        let (_, mut fti) = map(tuple((t_sign_u64, t_creator)), |(signature, creator)| {
             FileTypeIdentifier::new(signature, creator)
         })(&buffer)?;
+        fti.extra_data = buffer[FileTypeIdentifier::USED_SIZE..]
+            .iter()
+            .any(|b| *b != 0);
         Ok(fti)
     }
 }
 
+impl Serialise for FileTypeIdentifier {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), VhdxError> {
+        let mut buffer = vec![0; FileTypeIdentifier::SIZE];
+        buffer[..8].copy_from_slice(FileTypeIdentifier::SIGN);
+        self.creator
+            .encode_utf16()
+            .take(256)
+            .enumerate()
+            .for_each(|(i, c)| buffer[8 + i * 2..10 + i * 2].copy_from_slice(&c.to_le_bytes()));
+        writer.write_all(&buffer)?;
+        Ok(())
+    }
+}
+
 // Since the header is used to locate the log, updates to the headers cannot be made through the
 // log. To provide power failure consistency, there are two headers in every VHDX file. Each of the
 // two headers is a 4-KB structure that is aligned to a 64-KB boundary.<1> One header is stored at
@@ -118,14 +183,14 @@ pub struct Header {
     // its SequenceNumber field is greater than the other header's SequenceNumber field. The
     // implementation MUST only use data from the current header. If there is no current header,
     // then the VHDX file is corrupt.
-    seq_number: u64,
+    pub(crate) seq_number: u64,
 
     // Specifies a 128-bit unique identifier that identifies the file's contents. On every open of
     // a VHDX file, an implementation MUST change this GUID to a new and unique identifier before
     // the first modification is made to the file, including system and user metadata as well as
     // log playback. The implementation can skip updating this field if the storage media on which
     // the file is stored is read-only, or if the file is opened in read-only mode.
-    file_write_guid: Uuid,
+    pub(crate) file_write_guid: Uuid,
 
     // Specifies a 128-bit unique identifier that identifies the contents of the user visible data.
     // On every open of the VHDX file, an implementation MUST change this field to a new and unique
@@ -135,7 +200,7 @@ pub struct Header {
     // data, or disk size, or any block state transitions that will result in a virtual disk sector
     // read being different from a previous read. This does not include movement of blocks within a
     // file, which changes only the physical layout of the file, not the virtual identity.
-    data_write_guid: Uuid,
+    pub(crate) data_write_guid: Uuid,
 
     // Specifies a 128-bit unique identifier used to determine the validity of log entries. If this
     // field is zero, then the log is empty or has no valid entries and MUST not be replayed.
@@ -147,7 +212,7 @@ pub struct Header {
     // Specifies the version of the log format used within the VHDX file. This field MUST be set to
     // zero. If it is not, the implementation MUST NOT continue to process the file unless the
     // LogGuid field is zero, indicating that there is no log to replay.
-    log_version: u16,
+    pub(crate) log_version: u16,
 
     // Specifies the version of the VHDX format used within the VHDX file. This field MUST be set
     // to 1. If it is not, an implementation MUST NOT attempt to process the file using the details
@@ -166,7 +231,10 @@ pub struct Header {
 impl Header {
     const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
     pub const SIGN: &'static [u8] = &[0x68, 0x65, 0x61, 0x64];
-    fn new(
+    const SIZE: usize = 4096;
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
         signature: Signature,
         checksum: u32,
         seq_number: u64,
@@ -225,28 +293,51 @@ impl Validation for Header {
             return Err(VhdxError::VersionError(self.version));
         }
 
-        if self.log_version != 0 {
-            return Err(VhdxError::NotAllowedToBeZero("Header Log Version"));
+        // An unknown log version only matters when there is a log to replay, with a nil log
+        // guid the log is ignored anyway
+        if self.log_version != 0 && !self.log_guid.is_nil() {
+            return Err(VhdxError::LogVersionError(self.log_version));
         }
 
-        if self.log_length as u64 % Vhdx::MB != 0 {
-            return Err(VhdxError::NotDivisbleByMB(
-                "Header Log Length",
-                self.log_length.into(),
-            ));
+        Ok(())
+    }
+}
+
+impl Header {
+    // The log has to be placed on 1MB boundaries, kept apart from validate so the check can be
+    // relaxed for compact test images.
+    pub(crate) fn validate_alignment(&self) -> std::result::Result<(), VhdxError> {
+        if !(self.log_length as u64).is_multiple_of(Vhdx::MB) {
+            return Err(VhdxError::LogLengthError(self.log_length));
         }
 
-        if self.log_offset % Vhdx::MB != 0 {
-            return Err(VhdxError::NotDivisbleByMB(
-                "Header Log Offset",
-                self.log_offset,
-            ));
+        if !self.log_offset.is_multiple_of(Vhdx::MB) {
+            return Err(VhdxError::LogOffsetError(self.log_offset));
         }
 
         Ok(())
     }
 }
 
+impl Serialise for Header {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), VhdxError> {
+        let mut buffer = Vec::with_capacity(Header::SIZE);
+        buffer.extend_from_slice(Header::SIGN);
+        buffer.extend_from_slice(&self.crc32().to_le_bytes());
+        buffer.extend_from_slice(&self.seq_number.to_le_bytes());
+        buffer.extend_from_slice(&self.file_write_guid.to_bytes_le());
+        buffer.extend_from_slice(&self.data_write_guid.to_bytes_le());
+        buffer.extend_from_slice(&self.log_guid.to_bytes_le());
+        buffer.extend_from_slice(&self.log_version.to_le_bytes());
+        buffer.extend_from_slice(&self.version.to_le_bytes());
+        buffer.extend_from_slice(&self.log_length.to_le_bytes());
+        buffer.extend_from_slice(&self.log_offset.to_le_bytes());
+        buffer.resize(Header::SIZE, 0);
+        writer.write_all(&buffer)?;
+        Ok(())
+    }
+}
+
 fn parse_headers(buffer: &[u8]) -> IResult<&[u8], Header, VhdxParseError<&[u8]>> {
     map(
         tuple((
@@ -287,6 +378,13 @@ impl<T> DeSerialise<T> for Header {
     where
         T: Read + Seek,
     {
+        let offset = reader.stream_position()?;
+        Header::read_from(reader).map_err(|e| e.at_structure("Header", offset))
+    }
+}
+
+impl Header {
+    fn read_from<T: Read + Seek>(reader: &mut T) -> Result<Header, VhdxError> {
         let mut buffer = [0; (Vhdx::KB * 64) as usize];
         reader.read_exact(&mut buffer)?;
         let (_, headers) = parse_headers(&buffer)?;
@@ -305,29 +403,73 @@ pub struct RegionTable {
     signature: Signature,
     // A CRC-32C hash over the entire 64-KB table, with the Checksum field taking the value of zero
     // during the computation of the checksum value.
-    checksum: u32,
+    pub(crate) checksum: u32,
 
     // Specifies the number of valid entries to follow. This MUST be less than or equal to 2,047.
     entry_count: u32,
 
+    // Reserved (4 bytes): MUST be set to 0 and ignored.
+    pub(crate) reserved: u32,
+
     pub table_entries: BTreeMap<KnowRegion, RTEntry>,
+
+    // Order in which the entries are stored, the checksum covers them in that order
+    entry_order: Vec<KnowRegion>,
+
+    // Bytes after the last entry up to the end of the 64KB table, empty for a table created by
+    // this crate where they are all zero
+    trailing: Vec<u8>,
 }
 
 impl RegionTable {
     pub const SIGN: &'static [u8] = &[0x72, 0x65, 0x67, 0x69];
     const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+    const SIZE: usize = 64 * 1024;
 
-    const BAT_ENTRY: Uuid = uuid!("2DC27766F62342009D64115E9BFD4A08");
-    const META_DATA_ENTRY: Uuid = uuid!("8B7CA20647904B9AB8FE575F050F886E");
+    pub(crate) const BAT_ENTRY: Uuid = uuid!("2DC27766F62342009D64115E9BFD4A08");
+    pub(crate) const META_DATA_ENTRY: Uuid = uuid!("8B7CA20647904B9AB8FE575F050F886E");
 
     fn new(signature: Signature, checksum: u32, entry_count: u32) -> Self {
         Self {
             signature,
             checksum,
             entry_count,
+            reserved: 0,
             table_entries: BTreeMap::new(),
+            entry_order: Vec::new(),
+            trailing: Vec::new(),
+        }
+    }
+
+    // Entries in the order they are stored, entries added after parsing follow the parsed ones
+    fn ordered_entries(&self) -> impl Iterator<Item = &RTEntry> {
+        let stored = self
+            .entry_order
+            .iter()
+            .filter_map(|region| self.table_entries.get(region));
+        let added = self
+            .table_entries
+            .iter()
+            .filter(|(region, _)| !self.entry_order.contains(region))
+            .map(|(_, entry)| entry);
+        stored.chain(added)
+    }
+
+    fn trailing_bytes(&self) -> Vec<u8> {
+        let length = (RegionTable::SIZE - 16).saturating_sub(self.table_entries.len() * 32);
+        if self.trailing.len() == length {
+            self.trailing.clone()
+        } else {
+            vec![0; length]
         }
     }
+
+    pub(crate) fn with_entries(table_entries: BTreeMap<KnowRegion, RTEntry>) -> Self {
+        let mut region_table = RegionTable::new(Signature::Regi, 0, table_entries.len() as u32);
+        region_table.table_entries = table_entries;
+        region_table.checksum = region_table.crc32();
+        region_table
+    }
 }
 
 impl Validation for RegionTable {
@@ -339,13 +481,40 @@ impl Validation for RegionTable {
             ));
         }
 
+        if self.entry_count > 2047 {
+            return Err(VhdxError::RTEntryCountError(self.entry_count));
+        }
+
         let crc = self.crc32();
         if self.checksum != crc {
             return Err(VhdxError::Crc32Error(self.checksum, crc));
         }
 
-        if self.entry_count > 2047 {
-            return Err(VhdxError::RTEntryCountError(self.entry_count));
+        Ok(())
+    }
+}
+
+impl RegionTable {
+    // Every region must start at or after 1MB and start and end on a 1MB boundary
+    pub(crate) fn validate_alignment(&self) -> std::result::Result<(), VhdxError> {
+        for entry in self.table_entries.values() {
+            if entry.file_offset < Vhdx::MB {
+                return Err(VhdxError::RegionOffsetError(entry.file_offset));
+            }
+
+            if !entry.file_offset.is_multiple_of(Vhdx::MB) {
+                return Err(VhdxError::NotDivisbleByMB(
+                    "Region File Offset",
+                    entry.file_offset,
+                ));
+            }
+
+            if !(entry.length as u64).is_multiple_of(Vhdx::MB) {
+                return Err(VhdxError::NotDivisbleByMB(
+                    "Region Length",
+                    entry.length as u64,
+                ));
+            }
         }
 
         Ok(())
@@ -354,16 +523,8 @@ impl Validation for RegionTable {
 
 impl Crc32 for RegionTable {
     fn crc32(&self) -> u32 {
-        let mut length = Vhdx::KB * 64;
         let mut digest = RegionTable::CRC.digest();
         self.crc32_from_digest(&mut digest);
-        length -= 16;
-        self.table_entries.iter().for_each(|(_, entry)| {
-            entry.crc32_from_digest(&mut digest);
-            length -= 32;
-        });
-        let dead_space: Vec<u8> = iter::repeat(0).take(length as usize).collect();
-        digest.update(&dead_space);
         digest.finalize()
     }
 
@@ -371,7 +532,27 @@ impl Crc32 for RegionTable {
         digest.update(RegionTable::SIGN);
         digest.update(&[0; 4]);
         digest.update(&self.entry_count.to_le_bytes());
-        digest.update(&[0; 4]);
+        digest.update(&self.reserved.to_le_bytes());
+        self.ordered_entries()
+            .for_each(|entry| entry.crc32_from_digest(digest));
+        digest.update(&self.trailing_bytes());
+    }
+}
+
+impl Serialise for RegionTable {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), VhdxError> {
+        let mut buffer = Vec::with_capacity((Vhdx::KB * 64) as usize);
+        buffer.extend_from_slice(RegionTable::SIGN);
+        buffer.extend_from_slice(&self.crc32().to_le_bytes());
+        buffer.extend_from_slice(&self.entry_count.to_le_bytes());
+        buffer.extend_from_slice(&self.reserved.to_le_bytes());
+        for entry in self.ordered_entries() {
+            entry.serialize(&mut buffer)?;
+        }
+        buffer.extend_from_slice(&self.trailing_bytes());
+        buffer.resize(RegionTable::SIZE, 0);
+        writer.write_all(&buffer)?;
+        Ok(())
     }
 }
 
@@ -382,12 +563,21 @@ impl<T> DeSerialise<T> for RegionTable {
     where
         T: Read + Seek,
     {
+        let offset = reader.stream_position()?;
+        RegionTable::read_from(reader).map_err(|e| e.at_structure("Region Table", offset))
+    }
+}
+
+impl RegionTable {
+    fn read_from<T: Read + Seek>(reader: &mut T) -> Result<RegionTable, VhdxError> {
         let mut buffer = [0; 16];
         reader.read_exact(&mut buffer)?;
         let (_, mut header) = map(
             tuple((t_sign_u32, t_u32, t_u32, t_u32)),
-            |(signature, checksum, entry_count, _)| {
-                RegionTable::new(signature, checksum, entry_count)
+            |(signature, checksum, entry_count, reserved)| {
+                let mut table = RegionTable::new(signature, checksum, entry_count);
+                table.reserved = reserved;
+                table
             },
         )(&buffer)?;
         for _ in 0..header.entry_count {
@@ -395,11 +585,23 @@ impl<T> DeSerialise<T> for RegionTable {
             let known_region = match entry.guid {
                 RegionTable::BAT_ENTRY => Ok(KnowRegion::Bat),
                 RegionTable::META_DATA_ENTRY => Ok(KnowRegion::MetaData),
+                // Regions that are not required may be ignored by implementations not knowing them
+                guid if !entry.required => Ok(KnowRegion::Unknown(guid)),
                 _ => Err(VhdxError::UnknownRTEntryFound(entry.guid.to_string())),
             }?;
+            header.entry_order.push(known_region);
             header.table_entries.insert(known_region, entry);
         }
 
+        if header.table_entries.len() == header.entry_count as usize {
+            let length = (RegionTable::SIZE - 16).saturating_sub(header.table_entries.len() * 32);
+            // A table cut short by the end of the file is checked as if the rest was zero
+            reader
+                .by_ref()
+                .take(length as u64)
+                .read_to_end(&mut header.trailing)?;
+        }
+
         Ok(header)
     }
 }
@@ -422,7 +624,7 @@ pub struct RTEntry {
 }
 impl RTEntry {
     const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
-    fn new(guid: Uuid, file_offset: u64, length: u32, required: bool) -> Self {
+    pub(crate) fn new(guid: Uuid, file_offset: u64, length: u32, required: bool) -> Self {
         Self {
             guid,
             file_offset,
@@ -430,6 +632,10 @@ impl RTEntry {
             required,
         }
     }
+
+    pub fn length(&self) -> u32 {
+        self.length
+    }
 }
 
 impl Crc32 for RTEntry {
@@ -447,6 +653,16 @@ impl Crc32 for RTEntry {
     }
 }
 
+impl Serialise for RTEntry {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), VhdxError> {
+        writer.write_all(&self.guid.to_bytes_le())?;
+        writer.write_all(&self.file_offset.to_le_bytes())?;
+        writer.write_all(&self.length.to_le_bytes())?;
+        writer.write_all(&(self.required as u32).to_le_bytes())?;
+        Ok(())
+    }
+}
+
 impl<T> DeSerialise<T> for RTEntry {
     type Item = RTEntry;
 
@@ -466,10 +682,41 @@ impl<T> DeSerialise<T> for RTEntry {
     }
 }
 
-#[derive(Debug, Ord, PartialOrd, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, Ord, PartialOrd, PartialEq, Eq, Hash)]
 pub enum KnowRegion {
     Bat,
     MetaData,
+    // Optional region this implementation does not know
+    Unknown(Uuid),
+}
+
+impl KnowRegion {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            KnowRegion::Bat => "Bat",
+            KnowRegion::MetaData => "MetaData",
+            KnowRegion::Unknown(_) => "Unknown",
+        }
+    }
+}
+
+impl RegionTable {
+    // Issues that don't stop the table from being used, an unknown optional region is skipped and
+    // the reserved field is ignored.
+    pub(crate) fn conformance_issues(&self) -> Vec<VhdxError> {
+        let mut issues: Vec<VhdxError> = self
+            .table_entries
+            .keys()
+            .filter_map(|region| match region {
+                KnowRegion::Unknown(guid) => Some(VhdxError::UnknownRTEntryFound(guid.to_string())),
+                _ => None,
+            })
+            .collect();
+        if self.reserved != 0 {
+            issues.push(VhdxError::ReservedNotZero("Region Table"));
+        }
+        issues
+    }
 }
 
 #[cfg(test)]
@@ -556,10 +803,37 @@ mod tests {
 
         assert_eq!(Signature::Vhdxfile, fti.signature);
         assert_eq!("Microsoft Windows 10.0.19045.0", fti.creator);
+        assert!(!fti.has_extra_fti_data());
     }
 
     #[test]
-    fn parse_headers() {
+    fn fti_with_data_past_creator() {
+        let mut values = std::fs::read("test.vhdx").unwrap();
+        values.truncate(FileTypeIdentifier::SIZE);
+        values[0x1000] = 0x42;
+
+        let fti = FileTypeIdentifier::deserialize(&mut Cursor::new(values)).unwrap();
+        assert!(fti.has_extra_fti_data());
+    }
+
+    #[test]
+    fn fti_with_invalid_utf16_creator() {
+        let mut values = std::fs::read("test.vhdx").unwrap();
+        values.truncate(FileTypeIdentifier::SIZE);
+        // Unpaired high surrogate as first creator character
+        values[8..10].copy_from_slice(&0xD800_u16.to_le_bytes());
+
+        assert!(matches!(
+            crate::parse_utils::t_creator(&values[8..]),
+            Err(nom::Err::Error(VhdxParseError::Utf16(_)))
+        ));
+        assert!(matches!(
+            FileTypeIdentifier::deserialize(&mut Cursor::new(values)),
+            Err(VhdxError::ParseError(_))
+        ));
+    }
+
+    fn sample_header_bytes() -> Vec<u8> {
         let mut values = vec![
             0x68, 0x65, 0x61, 0x64, 0x6c, 0xef, 0x07, 0x80, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00,
             0x00, 0x00, 0xcc, 0xe0, 0x65, 0xb3, 0xaa, 0xf1, 0xd8, 0x4b, 0x9c, 0x8d, 0x16, 0x09,
@@ -571,8 +845,12 @@ mod tests {
         ];
 
         values.resize(Vhdx::KB as usize * 64, 0);
+        values
+    }
 
-        let mut values = Cursor::new(values);
+    #[test]
+    fn parse_headers() {
+        let mut values = Cursor::new(sample_header_bytes());
         let headers = Header::deserialize(&mut values).unwrap();
 
         assert_eq!(Signature::Head, headers.signature);
@@ -599,4 +877,108 @@ mod tests {
         assert_eq!(1048576, headers.log_length);
         assert_eq!(1048576, headers.log_offset);
     }
+
+    #[test]
+    fn header_round_trips() {
+        let bytes = sample_header_bytes();
+        let header = Header::deserialize(&mut Cursor::new(&bytes)).unwrap();
+
+        let mut serialized = Vec::new();
+        header.serialize(&mut serialized).unwrap();
+        // The checksum is recomputed and the reserved area written as zeros
+        assert_eq!(&bytes[..Header::SIZE], &serialized[..]);
+    }
+
+    #[test]
+    fn plain_crc32_differs_from_crc32c() {
+        let mut values = Cursor::new(sample_header_bytes());
+        let header = Header::deserialize(&mut values).unwrap();
+
+        let crc32c = header.crc32();
+        let plain = header.crc32_with(&crc::CRC_32_ISO_HDLC);
+
+        assert_eq!(header.checksum, crc32c);
+        assert_ne!(crc32c, plain);
+        assert_eq!(crc32c, header.crc32_with(&CRC_32_ISCSI));
+    }
+
+    #[test]
+    fn validate_layout_of_fixture() {
+        let mut file = std::fs::File::open("test.vhdx").unwrap();
+        VhdxHeader::validate_layout(&mut file).unwrap();
+    }
+
+    #[test]
+    fn validate_layout_detects_misplaced_header() {
+        const HEADER_2: usize = 128 * 1024;
+        let mut image = std::fs::read("test.vhdx").unwrap();
+        // Shift header 2 by one sector, leaving zeroes at its spec offset
+        let header = image[HEADER_2..HEADER_2 + 4096].to_vec();
+        image[HEADER_2..HEADER_2 + 4096].fill(0);
+        image[HEADER_2 + 4096..HEADER_2 + 8192].copy_from_slice(&header);
+
+        let result = VhdxHeader::validate_layout(&mut Cursor::new(image));
+        assert!(matches!(
+            result,
+            Err(VhdxError::StructureMisplaced("Header 2", 131072))
+        ));
+    }
+
+    fn fixture_region_table() -> Vec<u8> {
+        const REGION_TABLE_1: usize = 192 * 1024;
+        let image = std::fs::read("test.vhdx").unwrap();
+        image[REGION_TABLE_1..REGION_TABLE_1 + 64 * 1024].to_vec()
+    }
+
+    fn update_region_table_crc(table: &mut [u8]) {
+        table[4..8].fill(0);
+        let crc = Crc::<u32>::new(&CRC_32_ISCSI).checksum(table);
+        table[4..8].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn region_table_checksum_covers_stored_bytes() {
+        let mut table = fixture_region_table();
+        // Stores the metadata entry first and leaves data behind the entries
+        let (bat, meta_data) = table[16..80].split_at_mut(32);
+        bat.swap_with_slice(meta_data);
+        table[80] = 0xAB;
+        update_region_table_crc(&mut table);
+
+        let parsed = RegionTable::deserialize(&mut Cursor::new(&table)).unwrap();
+        parsed.validate().unwrap();
+        let mut written = Vec::new();
+        parsed.serialize(&mut written).unwrap();
+        assert_eq!(table, written);
+
+        // Corruption anywhere in the table is detected, including the trailing bytes
+        table[64 * 1024 - 1] = 0x01;
+        let parsed = RegionTable::deserialize(&mut Cursor::new(&table)).unwrap();
+        assert!(matches!(
+            parsed.validate(),
+            Err(VhdxError::Crc32Error(_, _))
+        ));
+    }
+
+    #[test]
+    fn truncated_structures_name_themselves() {
+        let result = Header::deserialize(&mut Cursor::new(vec![0; 100]));
+        assert!(matches!(
+            result,
+            Err(VhdxError::UnexpectedEof {
+                structure: "Header",
+                offset: 0
+            })
+        ));
+
+        // Cut off in the middle of the second entry
+        let mut reader = Cursor::new(fixture_region_table()[..60].to_vec());
+        assert!(matches!(
+            RegionTable::deserialize(&mut reader),
+            Err(VhdxError::UnexpectedEof {
+                structure: "Region Table",
+                offset: 0
+            })
+        ));
+    }
 }